@@ -0,0 +1,64 @@
+-- Optional PostGIS interop for pg_s2.
+--
+-- pg_s2 does not link against PostGIS; these wrappers exchange geometries
+-- through PostGIS's WKB I/O. Load after both extensions are installed:
+--
+--   CREATE EXTENSION postgis;
+--   CREATE EXTENSION pg_s2;
+--   \i sql/pg_s2_postgis.sql
+--
+-- Geometries in an SRID other than 0 or 4326 are transformed to 4326 first.
+
+CREATE OR REPLACE FUNCTION s2_cell_to_geometry(cell s2cellid)
+RETURNS geometry
+IMMUTABLE STRICT PARALLEL SAFE
+LANGUAGE SQL
+AS $$ SELECT ST_GeomFromEWKB(s2_cell_to_wkb($1, true)) $$;
+
+CREATE OR REPLACE FUNCTION s2_cover_geometry(
+    geom geometry,
+    level integer,
+    max_cells integer DEFAULT 8
+)
+RETURNS SETOF s2cellid
+STABLE STRICT PARALLEL SAFE
+LANGUAGE SQL
+AS $$
+    SELECT s2_cover_wkb(
+        ST_AsBinary(CASE WHEN ST_SRID($1) IN (0, 4326) THEN $1 ELSE ST_Transform($1, 4326) END),
+        $2,
+        $3
+    )
+$$;
+
+CREATE OR REPLACE FUNCTION s2_cover_geometry(geom geometry)
+RETURNS SETOF s2cellid
+STABLE STRICT PARALLEL SAFE
+LANGUAGE SQL
+AS $$
+    SELECT s2_cover_wkb(
+        ST_AsBinary(CASE WHEN ST_SRID($1) IN (0, 4326) THEN $1 ELSE ST_Transform($1, 4326) END)
+    )
+$$;
+
+CREATE OR REPLACE FUNCTION s2_lat_lng_to_cell(latlng geometry, level integer)
+RETURNS s2cellid
+IMMUTABLE STRICT PARALLEL SAFE
+LANGUAGE SQL
+AS $$
+    SELECT s2_lat_lng_to_cell(point(ST_X(g), ST_Y(g)), $2)
+    FROM (
+        SELECT CASE WHEN ST_SRID($1) IN (0, 4326) THEN $1 ELSE ST_Transform($1, 4326) END AS g
+    ) AS t
+$$;
+
+CREATE OR REPLACE FUNCTION s2_lat_lng_to_cell(latlng geometry)
+RETURNS s2cellid
+STABLE STRICT PARALLEL SAFE
+LANGUAGE SQL
+AS $$
+    SELECT s2_lat_lng_to_cell(point(ST_X(g), ST_Y(g)))
+    FROM (
+        SELECT CASE WHEN ST_SRID($1) IN (0, 4326) THEN $1 ELSE ST_Transform($1, 4326) END AS g
+    ) AS t
+$$;
@@ -1,7 +1,7 @@
 use pgrx::callconv::{ArgAbi, BoxRet};
-use pgrx::datum::Datum;
-use pgrx::guc::{GucContext, GucFlags, GucRegistry, GucSetting};
-use pgrx::iter::SetOfIterator;
+use pgrx::datum::{Datum, UnboxDatum};
+use pgrx::guc::{GucContext, GucFlags, GucRegistry, GucSetting, PostgresGucEnum};
+use pgrx::iter::{SetOfIterator, TableIterator};
 use pgrx::pg_sys::Point;
 use pgrx::pg_sys::BOX;
 use pgrx::pg_sys::Oid;
@@ -9,15 +9,25 @@ use pgrx::pgrx_sql_entity_graph::metadata::{
     ArgumentError, Returns, ReturnsError, SqlMapping, SqlTranslatable,
 };
 use pgrx::prelude::*;
-use pgrx::{rust_regtypein, StringInfo};
+use pgrx::{rust_regtypein, Internal, Json, JsonB, PgMemoryContexts, StringInfo};
 use s2::cap::Cap;
 use s2::cell::Cell;
-use s2::cellid::{CellID, NUM_FACES, POS_BITS};
+use s2::cellid::{CellID, MAX_SIZE, NUM_FACES, POS_BITS};
+use s2::cellunion::CellUnion;
+use s2::edgeutil::{distance_from_segment, interpolate, interpolate_at_distance, simple_crossing};
 use s2::latlng::LatLng;
-use s2::point::Point as S2Point;
-use s2::region::RegionCoverer;
+use s2::point::{point_area, true_centroid, Point as S2Point};
+use s2::r1::interval::Interval as R1Interval;
+use s2::r2::rect::Rect as R2Rect;
+use s2::r3::vector::Vector;
+use s2::region::{Region, RegionCoverer};
 use s2::rect::Rect;
-use s2::s1::{Angle, Rad};
+use s2::s1::interval::Interval as S1Interval;
+use s2::s1::{Angle, Deg, Rad};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::borrow::Cow;
+use std::collections::{BTreeMap, BTreeSet};
 use std::ffi::CStr;
 
 ::pgrx::pg_module_magic!(name, version);
@@ -25,7 +35,16 @@ use std::ffi::CStr;
 const S2CELLID_ORDER_MASK: u64 = 0x8000_0000_0000_0000;
 const S2CELLID_LSB_MASK: u64 = 0x1555_5555_5555_5555;
 const DEFAULT_MAX_CELLS: i32 = 8;
+const MAX_BOUNDARY_SEGMENTS: i32 = 1024;
 const EARTH_RADIUS_M_DEFAULT: f64 = 6_371_008.8;
+const WKB_POLYGON: u32 = 3;
+const WKB_MULTIPOLYGON: u32 = 6;
+const EWKB_SRID_FLAG: u32 = 0x2000_0000;
+const SRID_WGS84: u32 = 4326;
+const S2CELLUNION_VERSION: u8 = 1;
+const S2CELLUNION_BLOCK: usize = 32;
+const WGS84_A: f64 = 6_378_137.0;
+const WGS84_F: f64 = 1.0 / 298.257_223_563;
 static DEFAULT_LEVEL: GucSetting<i32> = GucSetting::<i32>::new(14);
 static EARTH_RADIUS_M: GucSetting<f64> = GucSetting::<f64>::new(EARTH_RADIUS_M_DEFAULT);
 static DEFAULT_LEVEL_NAME: &CStr =
@@ -42,7 +61,19 @@ static EARTH_RADIUS_M_SHORT: &CStr = unsafe {
 };
 static EARTH_RADIUS_M_DESC: &CStr = unsafe {
     CStr::from_bytes_with_nul_unchecked(
-        b"Used to convert between meters and radians in s2_great_circle_distance and s2_cover_cap.\0",
+        b"Used to convert between meters and radians when pg_s2.distance_model is sphere.\0",
+    )
+};
+static DISTANCE_MODEL: GucSetting<DistanceModel> =
+    GucSetting::<DistanceModel>::new(DistanceModel::Sphere);
+static DISTANCE_MODEL_NAME: &CStr =
+    unsafe { CStr::from_bytes_with_nul_unchecked(b"pg_s2.distance_model\0") };
+static DISTANCE_MODEL_SHORT: &CStr = unsafe {
+    CStr::from_bytes_with_nul_unchecked(b"Earth model for distances in meters: sphere or wgs84.\0")
+};
+static DISTANCE_MODEL_DESC: &CStr = unsafe {
+    CStr::from_bytes_with_nul_unchecked(
+        b"Used by s2_great_circle_distance and the meters-to-radians conversion in s2_cover_cap.\0",
     )
 };
 static DEFAULT_COVER_LEVEL: GucSetting<i32> = GucSetting::<i32>::new(12);
@@ -55,6 +86,16 @@ static DEFAULT_COVER_LEVEL_DESC: &CStr = unsafe {
     CStr::from_bytes_with_nul_unchecked(b"Used when cover level is not explicitly provided.\0")
 };
 
+/// `pg_s2.distance_model`: `sphere` uses `pg_s2.earth_radius_m`, `wgs84` uses
+/// geodesics on the WGS84 ellipsoid.
+#[derive(Copy, Clone, Debug, PartialEq, PostgresGucEnum)]
+enum DistanceModel {
+    #[name = c"sphere"]
+    Sphere,
+    #[name = c"wgs84"]
+    Wgs84,
+}
+
 #[pg_guard]
 pub extern "C-unwind" fn _PG_init() {
     GucRegistry::define_int_guc(
@@ -77,6 +118,14 @@ pub extern "C-unwind" fn _PG_init() {
         GucContext::Userset,
         GucFlags::default(),
     );
+    GucRegistry::define_enum_guc(
+        DISTANCE_MODEL_NAME,
+        DISTANCE_MODEL_SHORT,
+        DISTANCE_MODEL_DESC,
+        &DISTANCE_MODEL,
+        GucContext::Userset,
+        GucFlags::default(),
+    );
     GucRegistry::define_int_guc(
         DEFAULT_COVER_LEVEL_NAME,
         DEFAULT_COVER_LEVEL_SHORT,
@@ -182,6 +231,19 @@ where
     }
 }
 
+unsafe impl UnboxDatum for S2CellId {
+    type As<'src> = S2CellId;
+
+    unsafe fn unbox<'src>(datum: Datum<'src>) -> Self::As<'src>
+    where
+        Self: 'src,
+    {
+        S2CellId {
+            value: datum.sans_lifetime().value() as _,
+        }
+    }
+}
+
 unsafe impl BoxRet for S2CellId {
     unsafe fn box_into<'fcx>(self, fcinfo: &mut pgrx::callconv::FcInfo<'fcx>) -> Datum<'fcx> {
         fcinfo.return_raw_datum(pg_sys::Datum::from(self.value))
@@ -193,6 +255,16 @@ fn s2cellid_in(input: &CStr) -> S2CellId {
     let token = input
         .to_str()
         .unwrap_or_else(|_| error!("invalid s2cellid token"));
+    parse_s2cellid(token)
+}
+
+/// Parses either a hex token or a `face/digits` path string, as the type input does.
+fn parse_s2cellid(token: &str) -> S2CellId {
+    if token.contains('/') {
+        let cellid =
+            cellid_from_path_string(token).unwrap_or_else(|| error!("invalid s2cellid string"));
+        return S2CellId::from_u64(cellid.0);
+    }
     let cellid = CellID::from_token(token);
     if !s2_cellid_is_valid_raw(cellid.0) {
         error!("invalid s2cellid token");
@@ -231,17 +303,21 @@ CREATE TYPE s2cellid (
 extension_sql!(
     r#"
 CREATE CAST (s2cellid AS text) WITH FUNCTION s2_cell_to_token(s2cellid);
-CREATE CAST (text AS s2cellid) WITH FUNCTION s2_cell_from_token(text);
+CREATE CAST (text AS s2cellid) WITH FUNCTION s2_cell_from_text(text);
 CREATE CAST (s2cellid AS bigint) WITH FUNCTION s2_cell_to_bigint(s2cellid);
 CREATE CAST (bigint AS s2cellid) WITH FUNCTION s2_cell_from_bigint(bigint);
+CREATE CAST (s2cellid AS numeric) WITH FUNCTION s2_cell_to_numeric(s2cellid);
+CREATE CAST (numeric AS s2cellid) WITH FUNCTION s2_cell_from_numeric(numeric);
 "#,
     name = "s2cellid_casts",
     requires = [
         "concrete_type",
         s2_cell_to_token,
-        s2_cell_from_token,
+        s2_cell_from_text,
         s2_cell_to_bigint,
         s2_cell_from_bigint,
+        s2_cell_to_numeric,
+        s2_cell_from_numeric,
     ],
 );
 
@@ -268,6 +344,75 @@ fn s2_cell_to_token(cell: S2CellId) -> String {
     CellID(raw).to_token()
 }
 
+/// Formats a cell as `face/digits`, one child position digit per level
+/// (the S2 `ToString()` format, e.g. `4/0231`).
+fn cellid_to_path_string(cellid: CellID) -> String {
+    let mut out = String::with_capacity(2 + cellid.level() as usize);
+    out.push_str(&cellid.face().to_string());
+    out.push('/');
+    for level in 1..=cellid.level() {
+        out.push(char::from(b'0' + cellid.child_position(level) as u8));
+    }
+    out
+}
+
+/// Inverse of `cellid_to_path_string`. Only the exact form it produces is
+/// accepted: a single face digit `0`-`5`, `/`, then up to 30 digits `0`-`3`.
+fn cellid_from_path_string(input: &str) -> Option<CellID> {
+    let (face, digits) = input.split_once('/')?;
+    let &[face @ b'0'..=b'5'] = face.as_bytes() else {
+        return None;
+    };
+    if digits.len() > 30 {
+        return None;
+    }
+    let mut cellid = CellID::from_face(u64::from(face - b'0'));
+    for digit in digits.bytes() {
+        if !(b'0'..=b'3').contains(&digit) {
+            return None;
+        }
+        cellid = cellid.children()[(digit - b'0') as usize];
+    }
+    Some(cellid)
+}
+
+#[pg_extern(immutable)]
+fn s2_cell_to_string(cell: S2CellId) -> String {
+    let raw = cell.to_u64();
+    if !s2_cellid_is_valid_raw(raw) {
+        error!("invalid s2cellid");
+    }
+    cellid_to_path_string(CellID(raw))
+}
+
+#[pg_extern(immutable)]
+fn s2_cell_from_string(input: &str) -> S2CellId {
+    let cellid = cellid_from_path_string(input).unwrap_or_else(|| error!("invalid s2cellid string"));
+    S2CellId::from_u64(cellid.0)
+}
+
+/// Accepts a hex token or a `face/digits` path string; backs the `text` cast.
+#[pg_extern(immutable)]
+fn s2_cell_from_text(input: &str) -> S2CellId {
+    parse_s2cellid(input)
+}
+
+#[pg_extern(immutable)]
+fn s2_cells_to_tokens(cells: Vec<Option<S2CellId>>) -> Vec<Option<String>> {
+    cells
+        .into_iter()
+        .map(|cell| cell.map(s2_cell_to_token))
+        .collect()
+}
+
+#[pg_extern(immutable)]
+fn s2_cells_from_tokens(tokens: Vec<Option<String>>) -> Vec<Option<S2CellId>> {
+    tokens
+        .into_iter()
+        .map(|token| token.map(|t| s2_cell_from_token(&t)))
+        .collect()
+}
+
 #[pg_extern(immutable)]
 fn s2_cell_to_bigint(cell: S2CellId) -> i64 {
     cell.value
@@ -278,6 +423,30 @@ fn s2_cell_from_bigint(id: i64) -> S2CellId {
     S2CellId { value: id }
 }
 
+#[pg_extern(immutable)]
+fn s2_cell_to_int64_raw(cell: S2CellId) -> i64 {
+    cell.to_u64() as i64
+}
+
+#[pg_extern(immutable)]
+fn s2_cell_from_int64_raw(id: i64) -> S2CellId {
+    S2CellId::from_u64(id as u64)
+}
+
+#[pg_extern(immutable)]
+fn s2_cell_to_numeric(cell: S2CellId) -> AnyNumeric {
+    AnyNumeric::from(cell.to_u64())
+}
+
+#[pg_extern(immutable)]
+fn s2_cell_from_numeric(id: AnyNumeric) -> S2CellId {
+    let raw = id
+        .normalize()
+        .parse::<u64>()
+        .unwrap_or_else(|_| error!("invalid s2cellid numeric"));
+    S2CellId::from_u64(raw)
+}
+
 #[pg_extern(immutable)]
 fn s2_is_valid_cell(cell: S2CellId) -> bool {
     s2_cellid_is_valid_raw(cell.to_u64())
@@ -301,6 +470,73 @@ fn s2_get_face(cell: S2CellId) -> i32 {
     CellID(raw).face() as i32
 }
 
+#[pg_extern(immutable)]
+fn s2_cell_to_face_ij(
+    cell: S2CellId,
+) -> TableIterator<
+    'static,
+    (
+        name!(face, i32),
+        name!(i, i32),
+        name!(j, i32),
+        name!(orientation, i32),
+    ),
+> {
+    let raw = cell.to_u64();
+    if !s2_cellid_is_valid_raw(raw) {
+        error!("invalid s2cellid");
+    }
+    let (face, i, j, orientation) = CellID(raw).face_ij_orientation();
+    TableIterator::once((face as i32, i, j, orientation as i32))
+}
+
+#[pg_extern(immutable)]
+fn s2_cell_from_face_ij(face: i32, i: i32, j: i32, level: i32) -> S2CellId {
+    if !(0..NUM_FACES as i32).contains(&face) {
+        error!("invalid face");
+    }
+    if !(0..MAX_SIZE as i32).contains(&i) || !(0..MAX_SIZE as i32).contains(&j) {
+        error!("invalid ij");
+    }
+    if !(0..=30).contains(&level) {
+        error!("invalid level");
+    }
+    let cellid = CellID::from_face_ij(face as u8, i, j).parent(level as u64);
+    S2CellId::from_u64(cellid.0)
+}
+
+#[inline]
+fn r2_rect_to_box(rect: &R2Rect) -> BOX {
+    BOX {
+        low: Point {
+            x: rect.x.lo,
+            y: rect.y.lo,
+        },
+        high: Point {
+            x: rect.x.hi,
+            y: rect.y.hi,
+        },
+    }
+}
+
+#[pg_extern(immutable)]
+fn s2_cell_to_uv_bounds(cell: S2CellId) -> BOX {
+    let raw = cell.to_u64();
+    if !s2_cellid_is_valid_raw(raw) {
+        error!("invalid s2cellid");
+    }
+    r2_rect_to_box(&CellID(raw).bound_uv())
+}
+
+#[pg_extern(immutable)]
+fn s2_cell_to_st_bounds(cell: S2CellId) -> BOX {
+    let raw = cell.to_u64();
+    if !s2_cellid_is_valid_raw(raw) {
+        error!("invalid s2cellid");
+    }
+    r2_rect_to_box(&CellID(raw).bound_st())
+}
+
 #[pg_extern(immutable)]
 fn s2_lat_lng_to_cell(latlng: Point, level: i32) -> S2CellId {
     if !(0..=30).contains(&level) {
@@ -320,6 +556,17 @@ fn s2_lat_lng_to_cell_default(latlng: Point) -> S2CellId {
     s2_lat_lng_to_cell(latlng, level)
 }
 
+#[pg_extern(immutable)]
+fn s2_lat_lng_to_cells(latlngs: Vec<Option<Point>>, level: i32) -> Vec<Option<S2CellId>> {
+    if !(0..=30).contains(&level) {
+        error!("invalid level");
+    }
+    latlngs
+        .into_iter()
+        .map(|latlng| latlng.map(|p| s2_lat_lng_to_cell(p, level)))
+        .collect()
+}
+
 #[pg_extern(immutable)]
 fn s2_cell_to_lat_lng(cell: S2CellId) -> Point {
     let raw = cell.to_u64();
@@ -339,7 +586,12 @@ fn s2_cell_bbox(cell: S2CellId) -> BOX {
     if !s2_cellid_is_valid_raw(raw) {
         error!("invalid s2cellid");
     }
-    let rect = Cell::from(CellID(raw)).rect_bound();
+    rect_to_box(&Cell::from(CellID(raw)).rect_bound())
+}
+
+/// Planar box for a lat/lng rect. A `box` cannot wrap, so rects crossing the
+/// antimeridian widen to the full longitude range.
+fn rect_to_box(rect: &Rect) -> BOX {
     let (lng_lo, lng_hi) = if rect.is_inverted() {
         (-180.0, 180.0)
     } else {
@@ -356,14 +608,27 @@ fn s2_cell_bbox(cell: S2CellId) -> BOX {
     BOX { low, high }
 }
 
+fn box_to_rect(rect: &BOX) -> Rect {
+    let lat_lo = rect.low.y.min(rect.high.y);
+    let lat_hi = rect.low.y.max(rect.high.y);
+    let lng_lo = rect.low.x.min(rect.high.x);
+    let lng_hi = rect.low.x.max(rect.high.x);
+    Rect::from_degrees(lat_lo, lng_lo, lat_hi, lng_hi)
+}
+
 #[pg_extern(immutable)]
 fn s2_cell_to_vertices(cell: S2CellId) -> Vec<Point> {
     let raw = cell.to_u64();
     if !s2_cellid_is_valid_raw(raw) {
         error!("invalid s2cellid");
     }
-    let verts = Cell::from(CellID(raw)).vertices();
-    verts
+    cell_vertex_points(CellID(raw))
+}
+
+#[inline]
+fn cell_vertex_points(cellid: CellID) -> Vec<Point> {
+    Cell::from(cellid)
+        .vertices()
         .iter()
         .map(|v| {
             let ll = LatLng::from(*v);
@@ -399,673 +664,5296 @@ fn s2_cell_boundary_text(cell: S2CellId) -> String {
     if !s2_cellid_is_valid_raw(raw) {
         error!("invalid s2cellid");
     }
-    let verts = Cell::from(CellID(raw)).vertices();
-    let points: Vec<Point> = verts
-        .iter()
-        .map(|v| {
-            let ll = LatLng::from(*v);
-            Point {
-                x: ll.lng.deg(),
-                y: ll.lat.deg(),
-            }
-        })
-        .collect();
-    format_polygon_points(&points)
+    format_polygon_points(&cell_vertex_points(CellID(raw)))
+}
+
+/// Closed WKT ring, e.g. `(x0 y0, x1 y1, ..., x0 y0)`.
+fn wkt_ring(points: &[Point]) -> String {
+    let mut coords: Vec<String> = points.iter().map(|p| format!("{} {}", p.x, p.y)).collect();
+    if let Some(first) = coords.first().cloned() {
+        coords.push(first);
+    }
+    format!("({})", coords.join(", "))
+}
+
+/// Cell outline split at the antimeridian: one ring, or two when the cell
+/// crosses it (the same split as the GeoJSON output).
+fn cell_split_rings(cellid: CellID) -> Vec<Vec<Point>> {
+    let extended = extend_antimeridian(&cell_vertex_points(cellid), cell_pole_lat(cellid));
+    split_antimeridian(&extended)
+}
+
+fn push_wkb_header(out: &mut Vec<u8>, wkb_type: u32, srid: Option<u32>) {
+    out.push(1u8);
+    match srid {
+        Some(srid) => {
+            out.extend_from_slice(&(wkb_type | EWKB_SRID_FLAG).to_le_bytes());
+            out.extend_from_slice(&srid.to_le_bytes());
+        }
+        None => out.extend_from_slice(&wkb_type.to_le_bytes()),
+    }
+}
+
+fn push_wkb_polygon(out: &mut Vec<u8>, ring: &[Point], srid: Option<u32>) {
+    push_wkb_header(out, WKB_POLYGON, srid);
+    out.extend_from_slice(&1u32.to_le_bytes());
+    out.extend_from_slice(&(ring.len() as u32 + 1).to_le_bytes());
+    for p in ring.iter().chain(ring.first()) {
+        out.extend_from_slice(&p.x.to_le_bytes());
+        out.extend_from_slice(&p.y.to_le_bytes());
+    }
+}
+
+/// Little-endian WKB of the cell: a Polygon, or a MultiPolygon when it crosses
+/// the antimeridian. With `srid` set, the PostGIS EWKB variant carrying that SRID.
+fn cell_wkb(cellid: CellID, srid: Option<u32>) -> Vec<u8> {
+    let rings = cell_split_rings(cellid);
+    let mut out = Vec::with_capacity(13 + rings.len() * (13 + 16 * 8));
+    if let [ring] = rings.as_slice() {
+        push_wkb_polygon(&mut out, ring, srid);
+    } else {
+        push_wkb_header(&mut out, WKB_MULTIPOLYGON, srid);
+        out.extend_from_slice(&(rings.len() as u32).to_le_bytes());
+        for ring in &rings {
+            push_wkb_polygon(&mut out, ring, None);
+        }
+    }
+    out
 }
 
 #[pg_extern(immutable)]
-fn s2_cell_edge_neighbors(cell: S2CellId) -> Vec<S2CellId> {
+fn s2_cell_to_wkt(cell: S2CellId) -> String {
     let raw = cell.to_u64();
     if !s2_cellid_is_valid_raw(raw) {
         error!("invalid s2cellid");
     }
-    let neighbors = CellID(raw).edge_neighbors();
-    neighbors.into_iter().map(|n| S2CellId::from_u64(n.0)).collect()
+    let rings = cell_split_rings(CellID(raw));
+    if let [ring] = rings.as_slice() {
+        return format!("POLYGON({})", wkt_ring(ring));
+    }
+    let polygons: Vec<String> = rings.iter().map(|ring| format!("({})", wkt_ring(ring))).collect();
+    format!("MULTIPOLYGON({})", polygons.join(", "))
 }
 
 #[pg_extern(immutable)]
-fn s2_cell_all_neighbors(cell: S2CellId) -> Vec<S2CellId> {
+fn s2_cell_to_wkb(cell: S2CellId, ewkb: default!(bool, false)) -> Vec<u8> {
     let raw = cell.to_u64();
     if !s2_cellid_is_valid_raw(raw) {
         error!("invalid s2cellid");
     }
-    let cellid = CellID(raw);
-    let level = cellid.level();
-    cellid
-        .all_neighbors(level)
-        .into_iter()
-        .map(|n| S2CellId::from_u64(n.0))
-        .collect()
+    cell_wkb(CellID(raw), ewkb.then_some(SRID_WGS84))
 }
 
-#[pg_extern(stable)]
-fn s2_cover_rect(
-    rect: pg_sys::BOX,
-    level: i32,
-    max_cells: i32,
-) -> SetOfIterator<'static, S2CellId> {
-    if !(0..=30).contains(&level) {
-        error!("invalid level");
+/// Cells crossing the antimeridian contribute one polygon per side.
+#[pg_extern(immutable)]
+fn s2_cells_to_multipolygon_wkt(cells: Vec<Option<S2CellId>>) -> String {
+    let mut polygons = Vec::with_capacity(cells.len());
+    for cell in cells.into_iter().flatten() {
+        let raw = cell.to_u64();
+        if !s2_cellid_is_valid_raw(raw) {
+            error!("invalid s2cellid");
+        }
+        for ring in cell_split_rings(CellID(raw)) {
+            polygons.push(format!("({})", wkt_ring(&ring)));
+        }
     }
-    if max_cells <= 0 {
-        error!("invalid max_cells");
+    if polygons.is_empty() {
+        return "MULTIPOLYGON EMPTY".to_string();
     }
-    let lat_lo = rect.low.y.min(rect.high.y);
-    let lat_hi = rect.low.y.max(rect.high.y);
-    let lng_lo = rect.low.x.min(rect.high.x);
-    let lng_hi = rect.low.x.max(rect.high.x);
-    let s2_rect = Rect::from_degrees(lat_lo, lng_lo, lat_hi, lng_hi);
-    let coverer = RegionCoverer {
-        min_level: level as u8,
-        max_level: level as u8,
-        level_mod: 1,
-        max_cells: max_cells as usize,
-    };
-    let iter = coverer
-        .covering(&s2_rect)
-        .0
-        .into_iter()
-        .map(|c| S2CellId::from_u64(c.0));
-    SetOfIterator::new(iter)
+    format!("MULTIPOLYGON({})", polygons.join(", "))
 }
 
-#[pg_extern(stable, name = "s2_cover_rect")]
-fn s2_cover_rect_default(rect: pg_sys::BOX) -> SetOfIterator<'static, S2CellId> {
-    let level = DEFAULT_COVER_LEVEL.get();
-    s2_cover_rect(rect, level, DEFAULT_MAX_CELLS)
+/// Cell boundary with each edge split into `segments` great-circle pieces.
+fn cell_boundary_points(cellid: CellID, segments: i32) -> Vec<Point> {
+    let verts = Cell::from(cellid).vertices();
+    let mut out = Vec::with_capacity(4 * segments as usize);
+    for k in 0..4 {
+        let a = verts[k];
+        let b = verts[(k + 1) % 4];
+        for s in 0..segments {
+            let ll = LatLng::from(interpolate(s as f64 / segments as f64, &a, &b));
+            out.push(Point {
+                x: ll.lng.deg(),
+                y: ll.lat.deg(),
+            });
+        }
+    }
+    out
 }
 
-#[pg_extern(stable, name = "s2_cover_rect")]
-fn s2_cover_rect_with_default_max_cells(
-    rect: pg_sys::BOX,
-    level: i32,
-) -> SetOfIterator<'static, S2CellId> {
-    s2_cover_rect(rect, level, DEFAULT_MAX_CELLS)
+#[inline]
+fn unwrap_lng(lng: f64, prev: f64) -> f64 {
+    let mut x = lng;
+    while x - prev > 180.0 {
+        x -= 360.0;
+    }
+    while x - prev < -180.0 {
+        x += 360.0;
+    }
+    x
 }
 
-#[pg_extern(stable)]
-fn s2_cover_cap(
-    center: Point,
-    radius_m: f64,
-    level: i32,
-    max_cells: i32,
-) -> SetOfIterator<'static, S2CellId> {
-    if !(0..=30).contains(&level) {
-        error!("invalid level");
+/// Rewrites longitudes so that consecutive vertices never jump across the
+/// antimeridian (the SPEC "extend" form, e.g. 179 -> 181). A ring that winds
+/// around a pole is closed through `pole_lat`. Output longitudes start at or
+/// east of -180.
+fn extend_antimeridian(points: &[Point], pole_lat: f64) -> Vec<Point> {
+    let mut out: Vec<Point> = Vec::with_capacity(points.len() + 3);
+    for p in points {
+        let x = match out.last() {
+            Some(prev) => unwrap_lng(p.x, prev.x),
+            None => p.x,
+        };
+        out.push(Point { x, y: p.y });
     }
-    if max_cells <= 0 {
-        error!("invalid max_cells");
+    if let (Some(first), Some(last)) = (out.first().copied(), out.last().copied()) {
+        let closing = unwrap_lng(first.x, last.x);
+        if (closing - first.x).abs() > 180.0 {
+            out.push(Point {
+                x: closing,
+                y: first.y,
+            });
+            out.push(Point {
+                x: closing,
+                y: pole_lat,
+            });
+            out.push(Point {
+                x: first.x,
+                y: pole_lat,
+            });
+        }
     }
-    if radius_m < 0.0 {
-        error!("invalid radius");
+    if out.iter().any(|p| p.x < -180.0) {
+        for p in &mut out {
+            p.x += 360.0;
+        }
     }
-    let ll = LatLng::from_degrees(center.y, center.x);
-    if !ll.is_valid() {
-        error!("invalid latlng");
+    out
+}
+
+/// Point where the great circle through `a` and `b` meets meridian `lng`.
+/// Falls back to linear interpolation when the edge runs along a meridian or
+/// collapses to a pole.
+fn meridian_crossing(a: &Point, b: &Point, lng: f64) -> Point {
+    let pa = S2Point::from(LatLng::from_degrees(a.y, a.x));
+    let pb = S2Point::from(LatLng::from_degrees(b.y, b.x));
+    let (sin_l, cos_l) = lng.to_radians().sin_cos();
+    let meridian_normal = Vector::new(-sin_l, cos_l, 0.0);
+    let mut dir = pa.0.cross(&pb.0).cross(&meridian_normal);
+    if dir.norm() > 1e-15 {
+        if dir.x * cos_l + dir.y * sin_l < 0.0 {
+            dir = dir * -1.0;
+        }
+        return Point {
+            x: lng,
+            y: dir.z.atan2(dir.x.hypot(dir.y)).to_degrees(),
+        };
     }
-    let center_point = S2Point::from(ll);
-    let angle = Angle::from(Rad(radius_m / EARTH_RADIUS_M.get()));
-    let cap = Cap::from_center_angle(&center_point, &angle);
-    let coverer = RegionCoverer {
-        min_level: level as u8,
-        max_level: level as u8,
-        level_mod: 1,
-        max_cells: max_cells as usize,
+    let t = if b.x == a.x {
+        0.0
+    } else {
+        (lng - a.x) / (b.x - a.x)
     };
-    let iter = coverer
-        .covering(&cap)
-        .0
-        .into_iter()
-        .map(|c| S2CellId::from_u64(c.0));
-    SetOfIterator::new(iter)
+    Point {
+        x: lng,
+        y: a.y + t * (b.y - a.y),
+    }
 }
 
-#[pg_extern(stable, name = "s2_cover_cap")]
-fn s2_cover_cap_default(center: Point, radius_m: f64) -> SetOfIterator<'static, S2CellId> {
-    let level = DEFAULT_COVER_LEVEL.get();
-    s2_cover_cap(center, radius_m, level, DEFAULT_MAX_CELLS)
+#[inline]
+fn push_distinct(out: &mut Vec<Point>, p: Point) {
+    if out
+        .last()
+        .is_some_and(|last| (last.x - p.x).abs() < 1e-12 && (last.y - p.y).abs() < 1e-12)
+    {
+        return;
+    }
+    out.push(p);
 }
 
-#[pg_extern(stable, name = "s2_cover_cap")]
-fn s2_cover_cap_with_default_max_cells(
-    center: Point,
-    radius_m: f64,
-    level: i32,
-) -> SetOfIterator<'static, S2CellId> {
-    s2_cover_cap(center, radius_m, level, DEFAULT_MAX_CELLS)
+/// Sutherland-Hodgman clip of `ring` against the meridian `lng`, keeping the
+/// west (`x <= lng`) or east side.
+fn clip_ring_at_lng(ring: &[Point], lng: f64, keep_west: bool) -> Vec<Point> {
+    let inside = |p: &Point| if keep_west { p.x <= lng } else { p.x >= lng };
+    let mut out = Vec::with_capacity(ring.len() + 2);
+    for (idx, cur) in ring.iter().enumerate() {
+        let prev = &ring[(idx + ring.len() - 1) % ring.len()];
+        match (inside(prev), inside(cur)) {
+            (true, true) => push_distinct(&mut out, *cur),
+            (true, false) => push_distinct(&mut out, meridian_crossing(prev, cur, lng)),
+            (false, true) => {
+                push_distinct(&mut out, meridian_crossing(prev, cur, lng));
+                push_distinct(&mut out, *cur);
+            }
+            (false, false) => {}
+        }
+    }
+    if out.len() > 1 {
+        let first = out[0];
+        if out
+            .last()
+            .is_some_and(|last| (last.x - first.x).abs() < 1e-12 && (last.y - first.y).abs() < 1e-12)
+        {
+            out.pop();
+        }
+    }
+    out
 }
 
-extension_sql!(
-    r#"
-CREATE FUNCTION s2_cover_cap_ranges(
-    center point,
-    radius_m double precision,
-    level integer,
-    max_cells integer DEFAULT 8
-)
-RETURNS SETOF int8range
-STABLE PARALLEL SAFE
-LANGUAGE SQL
-AS $$
-    SELECT int8range(
-        s2_cell_to_bigint(s2_cell_range_min(cell)),
-        s2_cell_to_bigint(s2_cell_range_max(cell)),
-        '[]'
-    )
-    FROM s2_cover_cap($1, $2, $3, $4) AS cell
-$$;
-"#,
-    name = "s2_cover_cap_ranges",
-    requires = [s2_cover_cap, s2_cell_range_min, s2_cell_range_max, s2_cell_to_bigint],
-);
-
-extension_sql!(
-    r#"
-CREATE FUNCTION s2_cover_rect_ranges(
-    rect box,
-    level integer,
-    max_cells integer DEFAULT 8
-)
-RETURNS SETOF int8range
-STABLE PARALLEL SAFE
-LANGUAGE SQL
-AS $$
-    SELECT int8range(
-        s2_cell_to_bigint(s2_cell_range_min(cell)),
-        s2_cell_to_bigint(s2_cell_range_max(cell)),
-        '[]'
-    )
-    FROM s2_cover_rect($1, $2, $3) AS cell
-$$;
-"#,
-    name = "s2_cover_rect_ranges",
-    requires = [s2_cover_rect, s2_cell_range_min, s2_cell_range_max, s2_cell_to_bigint],
-);
-
-extension_sql!(
-    r#"
-CREATE FUNCTION s2_cell_to_boundary(cell s2cellid)
-RETURNS polygon
-IMMUTABLE PARALLEL SAFE
-LANGUAGE SQL
-AS $$ SELECT s2_cell_boundary_text($1)::polygon $$;
-"#,
-    name = "s2_cell_to_boundary",
-    requires = [s2_cell_boundary_text],
-);
-
-#[pg_extern(immutable)]
-fn s2_cell_range_min(cell: S2CellId) -> S2CellId {
-    let raw = cell.to_u64();
-    if !s2_cellid_is_valid_raw(raw) {
-        error!("invalid s2cellid");
+/// Splits an extended ring at +180 into parts that each stay within
+/// [-180, 180]. Parts without area (touching the line only) are dropped.
+fn split_antimeridian(ring: &[Point]) -> Vec<Vec<Point>> {
+    let west = clip_ring_at_lng(ring, 180.0, true);
+    let mut east = clip_ring_at_lng(ring, 180.0, false);
+    for p in &mut east {
+        p.x -= 360.0;
     }
-    let min = CellID(raw).range_min();
-    S2CellId::from_u64(min.0)
+    [west, east]
+        .into_iter()
+        .filter(|part| {
+            let (lo, hi) = part
+                .iter()
+                .fold((f64::INFINITY, f64::NEG_INFINITY), |(lo, hi), p| {
+                    (lo.min(p.x), hi.max(p.x))
+                });
+            part.len() >= 3 && hi > lo
+        })
+        .collect()
 }
 
-#[pg_extern(immutable)]
-fn s2_cell_range_max(cell: S2CellId) -> S2CellId {
-    let raw = cell.to_u64();
-    if !s2_cellid_is_valid_raw(raw) {
-        error!("invalid s2cellid");
+#[inline]
+fn cell_pole_lat(cellid: CellID) -> f64 {
+    if LatLng::from(cellid).lat.deg() >= 0.0 {
+        90.0
+    } else {
+        -90.0
     }
-    let max = CellID(raw).range_max();
-    S2CellId::from_u64(max.0)
 }
 
-#[pg_extern(immutable)]
-fn s2_cell_to_parent(cell: S2CellId, level: i32) -> S2CellId {
-    if !(0..=30).contains(&level) {
-        error!("invalid level");
-    }
+#[pg_extern(immutable, name = "s2_cell_boundary_text")]
+fn s2_cell_boundary_text_segments(cell: S2CellId, segments: i32, antimeridian: &str) -> String {
     let raw = cell.to_u64();
     if !s2_cellid_is_valid_raw(raw) {
         error!("invalid s2cellid");
     }
+    if !(1..=MAX_BOUNDARY_SEGMENTS).contains(&segments) {
+        error!("invalid segments");
+    }
     let cellid = CellID(raw);
-    let cell_level = cellid.level() as i32;
-    if level > cell_level {
-        error!("invalid level");
+    let points = cell_boundary_points(cellid, segments);
+    match antimeridian.trim().to_ascii_lowercase().as_str() {
+        "none" => format_polygon_points(&points),
+        "extend" => format_polygon_points(&extend_antimeridian(&points, cell_pole_lat(cellid))),
+        _ => error!("invalid antimeridian mode"),
     }
-    let parent = cellid.parent(level as u64);
-    S2CellId::from_u64(parent.0)
 }
 
-#[pg_extern(immutable, name = "s2_cell_to_parent")]
-fn s2_cell_to_parent_default(cell: S2CellId) -> S2CellId {
+#[pg_extern(immutable)]
+fn s2_cell_boundary_split_text(cell: S2CellId, segments: i32) -> Vec<String> {
     let raw = cell.to_u64();
     if !s2_cellid_is_valid_raw(raw) {
         error!("invalid s2cellid");
     }
+    if !(1..=MAX_BOUNDARY_SEGMENTS).contains(&segments) {
+        error!("invalid segments");
+    }
     let cellid = CellID(raw);
-    let level = cellid.level();
-    if level == 0 {
-        error!("invalid level");
+    let points = cell_boundary_points(cellid, segments);
+    let extended = extend_antimeridian(&points, cell_pole_lat(cellid));
+    split_antimeridian(&extended)
+        .iter()
+        .map(|part| format_polygon_points(part))
+        .collect()
+}
+
+/// Closes `ring` and orients it counterclockwise, as RFC 7946 requires for
+/// exterior rings.
+fn geojson_ring(ring: &[Point]) -> Value {
+    let twice_area: f64 = ring
+        .iter()
+        .zip(ring.iter().cycle().skip(1))
+        .map(|(p, q)| p.x * q.y - q.x * p.y)
+        .sum();
+    let mut coords: Vec<Value> = ring.iter().map(|p| json!([p.x, p.y])).collect();
+    if twice_area < 0.0 {
+        coords.reverse();
     }
-    s2_cell_to_parent(cell, level as i32 - 1)
+    if let Some(first) = coords.first().cloned() {
+        coords.push(first);
+    }
+    Value::Array(coords)
 }
 
-#[pg_extern(immutable)]
-fn s2_cell_to_children(cell: S2CellId, level: i32) -> SetOfIterator<'static, S2CellId> {
-    if !(0..=30).contains(&level) {
-        error!("invalid level");
+/// GeoJSON geometry of a cell: a Polygon, or a MultiPolygon when the cell
+/// crosses the antimeridian.
+fn cell_geojson_geometry(cellid: CellID) -> Value {
+    let parts = cell_split_rings(cellid);
+    if parts.len() == 1 {
+        json!({
+            "type": "Polygon",
+            "coordinates": [geojson_ring(&parts[0])],
+        })
+    } else {
+        let polygons: Vec<Value> = parts.iter().map(|part| json!([geojson_ring(part)])).collect();
+        json!({
+            "type": "MultiPolygon",
+            "coordinates": polygons,
+        })
     }
+}
+
+fn cell_geojson_feature(cellid: CellID, properties: Option<Value>) -> Value {
+    json!({
+        "type": "Feature",
+        "id": cellid.to_token(),
+        "geometry": cell_geojson_geometry(cellid),
+        "properties": properties.unwrap_or(Value::Null),
+    })
+}
+
+#[pg_extern(immutable)]
+fn s2_cell_to_geojson(cell: S2CellId) -> Json {
     let raw = cell.to_u64();
     if !s2_cellid_is_valid_raw(raw) {
         error!("invalid s2cellid");
     }
-    let cellid = CellID(raw);
-    let cell_level = cellid.level() as i32;
-    if level <= cell_level {
-        error!("invalid level");
-    }
-    let mut cur = cellid.child_begin_at_level(level as u64);
-    let end = cellid.child_end_at_level(level as u64);
-    let iter = std::iter::from_fn(move || {
-        if cur == end {
-            None
-        } else {
-            let out = S2CellId::from_u64(cur.0);
-            cur = cur.next();
-            Some(out)
+    Json(cell_geojson_geometry(CellID(raw)))
+}
+
+#[pg_extern(immutable)]
+fn s2_cells_to_geojson_feature_collection(
+    cells: Vec<Option<S2CellId>>,
+    properties: default!(Option<Vec<Option<JsonB>>>, "NULL"),
+) -> Json {
+    let properties: Vec<Option<JsonB>> = match properties {
+        Some(props) => {
+            if props.len() != cells.len() {
+                error!("properties length must match cells length");
+            }
+            props
         }
-    });
-    SetOfIterator::new(iter)
+        None => cells.iter().map(|_| None).collect(),
+    };
+    let mut features = Vec::with_capacity(cells.len());
+    for (cell, props) in cells.into_iter().zip(properties) {
+        let Some(cell) = cell else {
+            continue;
+        };
+        let raw = cell.to_u64();
+        if !s2_cellid_is_valid_raw(raw) {
+            error!("invalid s2cellid");
+        }
+        features.push(cell_geojson_feature(CellID(raw), props.map(|p| p.0)));
+    }
+    Json(json!({
+        "type": "FeatureCollection",
+        "features": features,
+    }))
 }
 
-#[pg_extern(immutable, name = "s2_cell_to_children")]
-fn s2_cell_to_children_default(cell: S2CellId) -> SetOfIterator<'static, S2CellId> {
-    let raw = cell.to_u64();
-    if !s2_cellid_is_valid_raw(raw) {
-        error!("invalid s2cellid");
+#[derive(AggregateName)]
+#[aggregate_name = "s2_geojson_agg"]
+pub struct S2GeoJsonAgg;
+
+#[pg_aggregate]
+impl Aggregate<S2GeoJsonAgg> for S2GeoJsonAgg {
+    type State = Internal;
+    type Args = (name!(cell, Option<S2CellId>), name!(properties, Option<JsonB>));
+    type Finalize = Option<Json>;
+
+    #[pgrx(immutable)]
+    fn state(
+        mut current: Self::State,
+        (cell, properties): Self::Args,
+        _fcinfo: pg_sys::FunctionCallInfo,
+    ) -> Self::State {
+        let features = unsafe { current.get_or_insert_default::<Vec<Value>>() };
+        if let Some(cell) = cell {
+            let raw = cell.to_u64();
+            if !s2_cellid_is_valid_raw(raw) {
+                error!("invalid s2cellid");
+            }
+            features.push(cell_geojson_feature(CellID(raw), properties.map(|p| p.0)));
+        }
+        current
     }
-    let cellid = CellID(raw);
-    let level = cellid.level();
-    if level == 30 {
-        error!("invalid level");
+
+    #[pgrx(immutable)]
+    fn finalize(
+        current: Self::State,
+        _direct_args: Self::OrderedSetArgs,
+        _fcinfo: pg_sys::FunctionCallInfo,
+    ) -> Self::Finalize {
+        let features = unsafe { current.get::<Vec<Value>>() }?;
+        Some(Json(json!({
+            "type": "FeatureCollection",
+            "features": features,
+        })))
     }
-    s2_cell_to_children(cell, level as i32 + 1)
 }
 
 #[pg_extern(immutable)]
-fn s2_cell_to_center_child(cell: S2CellId, level: i32) -> S2CellId {
-    if !(0..=30).contains(&level) {
-        error!("invalid level");
-    }
+fn s2_cell_edge_neighbors(cell: S2CellId) -> Vec<S2CellId> {
     let raw = cell.to_u64();
     if !s2_cellid_is_valid_raw(raw) {
         error!("invalid s2cellid");
     }
-    let cellid = CellID(raw);
-    let cell_level = cellid.level() as i32;
-    if level <= cell_level {
-        error!("invalid level");
-    }
-    let center = Cell::from(cellid).center();
-    let child = CellID::from(center).parent(level as u64);
-    S2CellId::from_u64(child.0)
+    let neighbors = CellID(raw).edge_neighbors();
+    neighbors.into_iter().map(|n| S2CellId::from_u64(n.0)).collect()
 }
 
-#[pg_extern(immutable, name = "s2_cell_to_center_child")]
-fn s2_cell_to_center_child_default(cell: S2CellId) -> S2CellId {
+#[pg_extern(immutable)]
+fn s2_cell_all_neighbors(cell: S2CellId) -> Vec<S2CellId> {
     let raw = cell.to_u64();
     if !s2_cellid_is_valid_raw(raw) {
         error!("invalid s2cellid");
     }
     let cellid = CellID(raw);
     let level = cellid.level();
-    if level == 30 {
-        error!("invalid level");
-    }
-    s2_cell_to_center_child(cell, level as i32 + 1)
+    cellid
+        .all_neighbors(level)
+        .into_iter()
+        .map(|n| S2CellId::from_u64(n.0))
+        .collect()
 }
 
-#[pg_extern(immutable)]
-fn s2_great_circle_distance(a: Point, b: Point, unit: &str) -> f64 {
-    let ll_a = LatLng::from_degrees(a.y, a.x);
-    let ll_b = LatLng::from_degrees(b.y, b.x);
-    if !ll_a.is_valid() || !ll_b.is_valid() {
-        error!("invalid latlng");
+#[pg_extern(stable)]
+fn s2_cover_rect(
+    rect: pg_sys::BOX,
+    level: i32,
+    max_cells: i32,
+) -> SetOfIterator<'static, S2CellId> {
+    if !(0..=30).contains(&level) {
+        error!("invalid level");
     }
-    let angle = ll_a.distance(&ll_b).rad();
-    let earth_radius = EARTH_RADIUS_M.get();
-    match unit.trim().to_ascii_lowercase().as_str() {
-        "m" => angle * earth_radius,
-        "km" => angle * earth_radius / 1000.0,
-        "rad" => angle,
-        _ => error!("invalid unit"),
+    if max_cells <= 0 {
+        error!("invalid max_cells");
     }
+    let s2_rect = box_to_rect(&rect);
+    let coverer = RegionCoverer {
+        min_level: level as u8,
+        max_level: level as u8,
+        level_mod: 1,
+        max_cells: max_cells as usize,
+    };
+    let iter = coverer
+        .covering(&s2_rect)
+        .0
+        .into_iter()
+        .map(|c| S2CellId::from_u64(c.0));
+    SetOfIterator::new(iter)
 }
 
-#[pg_extern(immutable, name = "s2_great_circle_distance")]
-fn s2_great_circle_distance_default(a: Point, b: Point) -> f64 {
-    s2_great_circle_distance(a, b, "m")
+#[pg_extern(stable, name = "s2_cover_rect")]
+fn s2_cover_rect_default(rect: pg_sys::BOX) -> SetOfIterator<'static, S2CellId> {
+    let level = DEFAULT_COVER_LEVEL.get();
+    s2_cover_rect(rect, level, DEFAULT_MAX_CELLS)
 }
 
-#[cfg(any(test, feature = "pg_test"))]
-#[pg_schema]
-mod tests {
-    use super::*;
-    use s2::cell::Cell;
-    use s2::cellid::CellID;
-    use s2::latlng::LatLng;
-    use pgrx::spi::Spi;
+#[pg_extern(stable, name = "s2_cover_rect")]
+fn s2_cover_rect_with_default_max_cells(
+    rect: pg_sys::BOX,
+    level: i32,
+) -> SetOfIterator<'static, S2CellId> {
+    s2_cover_rect(rect, level, DEFAULT_MAX_CELLS)
+}
 
-    #[pg_test]
-    fn test_s2_get_extension_version_matches_pkg() {
-        let v = s2_get_extension_version();
-        assert_eq!(v, env!("CARGO_PKG_VERSION"));
+#[pg_extern(stable)]
+fn s2_cover_cap(
+    center: Point,
+    radius_m: f64,
+    level: i32,
+    max_cells: i32,
+) -> SetOfIterator<'static, S2CellId> {
+    if !(0..=30).contains(&level) {
+        error!("invalid level");
     }
-
-    #[pg_test]
-    fn test_i64_norm_roundtrip() {
-        let cases = [
-            0u64,
+    if max_cells <= 0 {
+        error!("invalid max_cells");
+    }
+    let cap = cap_from_center_radius(center, radius_m);
+    let coverer = RegionCoverer {
+        min_level: level as u8,
+        max_level: level as u8,
+        level_mod: 1,
+        max_cells: max_cells as usize,
+    };
+    let iter = coverer
+        .covering(&cap)
+        .0
+        .into_iter()
+        .map(|c| S2CellId::from_u64(c.0));
+    SetOfIterator::new(iter)
+}
+
+fn cap_from_center_radius(center: Point, radius_m: f64) -> Cap {
+    cap_from_center_radius_in(center, radius_m, UnitScale::Surface(1.0))
+}
+
+fn cap_from_center_radius_in(center: Point, radius: f64, scale: UnitScale) -> Cap {
+    if radius < 0.0 {
+        error!("invalid radius");
+    }
+    let ll = LatLng::from_degrees(center.y, center.x);
+    if !ll.is_valid() {
+        error!("invalid latlng");
+    }
+    let radians = match scale {
+        UnitScale::Surface(meters) => radius * meters / model_earth_radius_m(),
+        UnitScale::Sphere(radians) => radius * radians,
+    };
+    Cap::from_center_angle(&S2Point::from(ll), &Angle::from(Rad(radians)))
+}
+
+/// `s2_cover_cap` with the radius given in any length unit (`m`, `km`, `mi`,
+/// `nmi`, `ft`, `rad`, `deg`).
+#[pg_extern(stable, name = "s2_cover_cap")]
+fn s2_cover_cap_unit(
+    center: Point,
+    radius: f64,
+    unit: &str,
+    level: i32,
+    max_cells: default!(i32, 8),
+) -> SetOfIterator<'static, S2CellId> {
+    let coverer = fixed_level_coverer(level, max_cells);
+    let cap = cap_from_center_radius_in(center, radius, parse_unit(unit, LENGTH_UNITS));
+    cells_to_setof(coverer.covering(&cap).0)
+}
+
+#[pg_extern(stable, name = "s2_cover_cap")]
+fn s2_cover_cap_default(center: Point, radius_m: f64) -> SetOfIterator<'static, S2CellId> {
+    let level = DEFAULT_COVER_LEVEL.get();
+    s2_cover_cap(center, radius_m, level, DEFAULT_MAX_CELLS)
+}
+
+#[pg_extern(stable, name = "s2_cover_cap")]
+fn s2_cover_cap_with_default_max_cells(
+    center: Point,
+    radius_m: f64,
+    level: i32,
+) -> SetOfIterator<'static, S2CellId> {
+    s2_cover_cap(center, radius_m, level, DEFAULT_MAX_CELLS)
+}
+
+/// Spherical cap: center in degrees and an opening angle in degrees of arc.
+/// Text form is `<(lng,lat),radius_deg>`.
+#[derive(Copy, Clone, Debug, Serialize, Deserialize, PostgresType)]
+#[inoutfuncs]
+pub struct S2Cap {
+    lng: f64,
+    lat: f64,
+    radius_deg: f64,
+}
+
+impl S2Cap {
+    fn from_cap(cap: &Cap) -> Self {
+        let ll = LatLng::from(*cap.center());
+        S2Cap {
+            lng: ll.lng.deg(),
+            lat: ll.lat.deg(),
+            radius_deg: cap.radius().deg(),
+        }
+    }
+
+    fn to_cap(self) -> Cap {
+        let center = S2Point::from(LatLng::from_degrees(self.lat, self.lng));
+        Cap::from_center_angle(&center, &Angle::from(Deg(self.radius_deg)))
+    }
+}
+
+impl InOutFuncs for S2Cap {
+    fn input(input: &CStr) -> Self {
+        let numbers = input
+            .to_str()
+            .ok()
+            .and_then(|text| parse_shape(text, "<(#,#),#>"))
+            .unwrap_or_default();
+        let [lng, lat, radius_deg] = numbers[..] else {
+            error!("invalid s2cap");
+        };
+        if !LatLng::from_degrees(lat, lng).is_valid() || !(0.0..=180.0).contains(&radius_deg) {
+            error!("invalid s2cap");
+        }
+        S2Cap {
+            lng,
+            lat,
+            radius_deg,
+        }
+    }
+
+    fn output(&self, buffer: &mut StringInfo) {
+        buffer.push_str(&format!("<({},{}),{}>", self.lng, self.lat, self.radius_deg));
+    }
+}
+
+/// Latitude/longitude rectangle in degrees. `lng_lo > lng_hi` means the
+/// rectangle wraps across the antimeridian. Text form is
+/// `((lng_lo,lat_lo),(lng_hi,lat_hi))`; unlike `box`, corners are never
+/// reordered.
+#[derive(Copy, Clone, Debug, Serialize, Deserialize, PostgresType)]
+#[inoutfuncs]
+pub struct S2LatLngRect {
+    lng_lo: f64,
+    lat_lo: f64,
+    lng_hi: f64,
+    lat_hi: f64,
+}
+
+impl S2LatLngRect {
+    fn from_rect(rect: &Rect) -> Self {
+        S2LatLngRect {
+            lng_lo: rect.lng_lo().deg(),
+            lat_lo: rect.lat_lo().deg(),
+            lng_hi: rect.lng_hi().deg(),
+            lat_hi: rect.lat_hi().deg(),
+        }
+    }
+
+    fn to_rect(self) -> Rect {
+        Rect::from_degrees(self.lat_lo, self.lng_lo, self.lat_hi, self.lng_hi)
+    }
+
+    fn new_checked(lng_lo: f64, lat_lo: f64, lng_hi: f64, lat_hi: f64) -> Self {
+        if !(-90.0..=90.0).contains(&lat_lo)
+            || !(-90.0..=90.0).contains(&lat_hi)
+            || !(-180.0..=180.0).contains(&lng_lo)
+            || !(-180.0..=180.0).contains(&lng_hi)
+            || lat_lo > lat_hi
+        {
+            error!("invalid s2latlngrect");
+        }
+        S2LatLngRect {
+            lng_lo,
+            lat_lo,
+            lng_hi,
+            lat_hi,
+        }
+    }
+}
+
+impl InOutFuncs for S2LatLngRect {
+    fn input(input: &CStr) -> Self {
+        let numbers = input
+            .to_str()
+            .ok()
+            .and_then(|text| parse_shape(text, "((#,#),(#,#))"))
+            .unwrap_or_default();
+        let [lng_lo, lat_lo, lng_hi, lat_hi] = numbers[..] else {
+            error!("invalid s2latlngrect");
+        };
+        S2LatLngRect::new_checked(lng_lo, lat_lo, lng_hi, lat_hi)
+    }
+
+    fn output(&self, buffer: &mut StringInfo) {
+        buffer.push_str(&format!(
+            "(({},{}),({},{}))",
+            self.lng_lo, self.lat_lo, self.lng_hi, self.lat_hi
+        ));
+    }
+}
+
+/// Numbers of a literal that must match `shape` exactly, where `#` stands
+/// for one number, e.g. `<(#,#),#>`. Whitespace is allowed around tokens;
+/// empty fields and stray delimiters are rejected.
+fn parse_shape(input: &str, shape: &str) -> Option<Vec<f64>> {
+    let is_delimiter = |c: char| c.is_whitespace() || shape.contains(c);
+    let mut rest = input;
+    let mut numbers = Vec::new();
+    for expected in shape.chars() {
+        rest = rest.trim_start();
+        if expected == '#' {
+            let end = rest.find(is_delimiter).unwrap_or(rest.len());
+            if end == 0 {
+                return None;
+            }
+            numbers.push(rest[..end].parse::<f64>().ok()?);
+            rest = &rest[end..];
+        } else {
+            rest = rest.strip_prefix(expected)?;
+        }
+    }
+    rest.trim().is_empty().then_some(numbers)
+}
+
+/// Numbers of a delimited literal such as `[(1,2),(3,4)]`.
+fn parse_numbers(input: &str, delimiters: &[char]) -> Option<Vec<f64>> {
+    input
+        .split(delimiters)
+        .map(str::trim)
+        .filter(|part| !part.is_empty())
+        .map(|part| part.parse::<f64>().ok())
+        .collect()
+}
+
+/// Arc angle for a margin of `meters` anywhere on the globe.
+fn meters_to_angle(meters: f64) -> Angle {
+    Angle::from(Rad(meters / model_earth_radius_m()))
+}
+
+/// Smallest cap enclosing both caps. (`Cap::union` in the s2 crate measures
+/// from the wrong center when the second cap is the larger one.)
+fn cap_union(a: &Cap, b: &Cap) -> Cap {
+    let (a, b) = if a.radius().rad() >= b.radius().rad() {
+        (a, b)
+    } else {
+        (b, a)
+    };
+    if a.is_full() || b.is_empty() {
+        return a.clone();
+    }
+    let (a_radius, b_radius) = (a.radius().rad(), b.radius().rad());
+    let distance = a.center().distance(b.center()).rad();
+    if a_radius >= distance + b_radius {
+        return a.clone();
+    }
+    let radius = (0.5 * (distance + a_radius + b_radius)).min(std::f64::consts::PI);
+    let center = interpolate_at_distance(
+        &Angle::from(Rad(0.5 * (distance - a_radius + b_radius))),
+        a.center(),
+        b.center(),
+    );
+    Cap::from_center_angle(&center, &Angle::from(Rad(radius)))
+}
+
+/// Latitude/longitude bound of a cap. (`Cap::rect_bound` in the s2 crate takes
+/// the sine of the squared chord length and clamps the south pole to +90°.)
+fn cap_rect_bound(cap: &Cap) -> Rect {
+    if cap.is_empty() {
+        return Rect::empty();
+    }
+    let radius = cap.radius().rad();
+    let center = LatLng::from(*cap.center());
+    let (center_lat, center_lng) = (center.lat.rad(), center.lng.rad());
+    let lat_lo = center_lat - radius;
+    let lat_hi = center_lat + radius;
+    let half_pi = std::f64::consts::FRAC_PI_2;
+    if lat_lo <= -half_pi || lat_hi >= half_pi {
+        return Rect::from_degrees(
+            lat_lo.max(-half_pi).to_degrees(),
+            -180.0,
+            lat_hi.min(half_pi).to_degrees(),
+            180.0,
+        );
+    }
+    // Law of sines on the triangle formed by the pole, the cap center and the
+    // point where the cap touches a meridian.
+    let (sin_a, sin_c) = (radius.sin(), center_lat.cos());
+    if sin_a > sin_c {
+        return Rect::from_degrees(lat_lo.to_degrees(), -180.0, lat_hi.to_degrees(), 180.0);
+    }
+    let angle_a = (sin_a / sin_c).asin();
+    Rect::from_degrees(
+        lat_lo.to_degrees(),
+        wrap_lng_degrees((center_lng - angle_a).to_degrees()),
+        lat_hi.to_degrees(),
+        wrap_lng_degrees((center_lng + angle_a).to_degrees()),
+    )
+}
+
+fn wrap_lng_degrees(lng: f64) -> f64 {
+    if lng > 180.0 {
+        lng - 360.0
+    } else if lng < -180.0 {
+        lng + 360.0
+    } else {
+        lng
+    }
+}
+
+/// Rect grown so it contains every point within `distance` of the original:
+/// latitude by the distance, longitude by the widest margin that distance
+/// spans at the rect's most poleward latitude.
+fn rect_expanded_by_distance(rect: &Rect, distance: &Angle) -> Rect {
+    let d = distance.rad();
+    let max_abs_lat = rect.lat_lo().rad().abs().max(rect.lat_hi().rad().abs());
+    let (sin_d, cos_lat) = (d.sin(), max_abs_lat.cos());
+    let lng_margin = if d < std::f64::consts::FRAC_PI_2 && sin_d < cos_lat {
+        (sin_d / cos_lat).asin()
+    } else {
+        std::f64::consts::PI
+    };
+    let margin = LatLng {
+        lat: Angle::from(Rad(d)),
+        lng: Angle::from(Rad(lng_margin)),
+    };
+    rect.expanded(&margin).polar_closure()
+}
+
+#[pg_extern(stable)]
+fn s2_cap(center: Point, radius_m: f64) -> S2Cap {
+    S2Cap::from_cap(&cap_from_center_radius(center, radius_m))
+}
+
+#[pg_extern(immutable)]
+fn s2_cap_center(cap: S2Cap) -> Point {
+    Point {
+        x: cap.lng,
+        y: cap.lat,
+    }
+}
+
+/// Cap radius in meters. Under `wgs84` this is the cap angle times the
+/// smallest radius of curvature (the conversion `s2_cap` uses), a lower bound
+/// rather than the geodesic distance to the cap edge, which is up to ~0.7%
+/// longer depending on direction and latitude.
+#[pg_extern(stable)]
+fn s2_cap_radius_m(cap: S2Cap) -> f64 {
+    cap.radius_deg.to_radians() * model_earth_radius_m()
+}
+
+#[pg_extern(immutable)]
+fn s2_cap_contains(cap: S2Cap, latlng: Point) -> bool {
+    let ll = LatLng::from_degrees(latlng.y, latlng.x);
+    if !ll.is_valid() {
+        error!("invalid latlng");
+    }
+    cap.to_cap().contains_point(&S2Point::from(ll))
+}
+
+#[pg_extern(immutable)]
+fn s2_cap_intersects_cell(cap: S2Cap, cell: S2CellId) -> bool {
+    let raw = cell.to_u64();
+    if !s2_cellid_is_valid_raw(raw) {
+        error!("invalid s2cellid");
+    }
+    cap.to_cap().intersects_cell(&Cell::from(CellID(raw)))
+}
+
+#[pg_extern(immutable)]
+fn s2_cap_union(a: S2Cap, b: S2Cap) -> S2Cap {
+    S2Cap::from_cap(&cap_union(&a.to_cap(), &b.to_cap()))
+}
+
+#[pg_extern(stable)]
+fn s2_cap_expanded(cap: S2Cap, meters: f64) -> S2Cap {
+    if meters < 0.0 {
+        error!("invalid distance");
+    }
+    S2Cap::from_cap(&cap.to_cap().expanded(&meters_to_angle(meters)))
+}
+
+#[pg_extern(immutable)]
+fn s2_cap_rect_bound(cap: S2Cap) -> S2LatLngRect {
+    S2LatLngRect::from_rect(&cap_rect_bound(&cap.to_cap()))
+}
+
+#[pg_extern(immutable)]
+fn s2_latlngrect(lo: Point, hi: Point) -> S2LatLngRect {
+    S2LatLngRect::new_checked(lo.x, lo.y, hi.x, hi.y)
+}
+
+/// Bounding box of a cap for prefiltering plain lat/lng columns. Caps reaching
+/// a pole span every longitude; caps crossing ±180° widen to -180..180.
+#[pg_extern(stable)]
+fn s2_cap_bbox(center: Point, radius_m: f64) -> BOX {
+    rect_to_box(&cap_rect_bound(&cap_from_center_radius(center, radius_m)))
+}
+
+#[pg_extern(stable)]
+fn s2_rect_expand(rect: BOX, meters: f64) -> BOX {
+    if meters < 0.0 {
+        error!("invalid distance");
+    }
+    let s2_rect = box_to_rect(&rect);
+    if !s2_rect.is_valid() {
+        error!("invalid rect");
+    }
+    rect_to_box(&rect_expanded_by_distance(&s2_rect, &meters_to_angle(meters)))
+}
+
+#[pg_extern(immutable, name = "s2_latlngrect")]
+fn s2_latlngrect_from_box(rect: BOX) -> S2LatLngRect {
+    S2LatLngRect::new_checked(
+        rect.low.x.min(rect.high.x),
+        rect.low.y.min(rect.high.y),
+        rect.low.x.max(rect.high.x),
+        rect.low.y.max(rect.high.y),
+    )
+}
+
+#[pg_extern(immutable)]
+fn s2_latlngrect_contains(rect: S2LatLngRect, latlng: Point) -> bool {
+    let ll = LatLng::from_degrees(latlng.y, latlng.x);
+    if !ll.is_valid() {
+        error!("invalid latlng");
+    }
+    rect.to_rect().contains_latlng(&ll)
+}
+
+#[pg_extern(immutable)]
+fn s2_latlngrect_intersects_cell(rect: S2LatLngRect, cell: S2CellId) -> bool {
+    let raw = cell.to_u64();
+    if !s2_cellid_is_valid_raw(raw) {
+        error!("invalid s2cellid");
+    }
+    rect.to_rect().intersects_cell(&Cell::from(CellID(raw)))
+}
+
+#[pg_extern(immutable)]
+fn s2_latlngrect_union(a: S2LatLngRect, b: S2LatLngRect) -> S2LatLngRect {
+    S2LatLngRect::from_rect(&a.to_rect().union(&b.to_rect()))
+}
+
+#[pg_extern(stable)]
+fn s2_latlngrect_expanded(rect: S2LatLngRect, meters: f64) -> S2LatLngRect {
+    if meters < 0.0 {
+        error!("invalid distance");
+    }
+    S2LatLngRect::from_rect(&rect_expanded_by_distance(&rect.to_rect(), &meters_to_angle(meters)))
+}
+
+#[pg_extern(immutable)]
+fn s2_latlngrect_cap_bound(rect: S2LatLngRect) -> S2Cap {
+    S2Cap::from_cap(&rect.to_rect().cap_bound())
+}
+
+/// The cell's latitude/longitude bound. Unlike `s2_cell_bbox`, cells touching
+/// ±180° keep their wrap-around longitude interval and polar cells span every
+/// longitude.
+#[pg_extern(immutable)]
+fn s2_cell_rect_bound(cell: S2CellId) -> S2LatLngRect {
+    let raw = cell.to_u64();
+    if !s2_cellid_is_valid_raw(raw) {
+        error!("invalid s2cellid");
+    }
+    S2LatLngRect::from_rect(&Cell::from(CellID(raw)).rect_bound())
+}
+
+#[pg_extern(stable, name = "s2_cell_rect_bound")]
+fn s2_cell_rect_bound_expanded(cell: S2CellId, meters: f64) -> S2LatLngRect {
+    let raw = cell.to_u64();
+    if !s2_cellid_is_valid_raw(raw) {
+        error!("invalid s2cellid");
+    }
+    if meters < 0.0 {
+        error!("invalid distance");
+    }
+    let rect = Cell::from(CellID(raw)).rect_bound();
+    S2LatLngRect::from_rect(&rect_expanded_by_distance(&rect, &meters_to_angle(meters)))
+}
+
+#[pg_extern(immutable, name = "s2_cover_cap")]
+fn s2_cover_s2cap(
+    cap: S2Cap,
+    level: i32,
+    max_cells: default!(i32, 8),
+) -> SetOfIterator<'static, S2CellId> {
+    let coverer = fixed_level_coverer(level, max_cells);
+    cells_to_setof(coverer.covering(&cap.to_cap()).0)
+}
+
+#[pg_extern(immutable, name = "s2_cover_rect")]
+fn s2_cover_s2latlngrect(
+    rect: S2LatLngRect,
+    level: i32,
+    max_cells: default!(i32, 8),
+) -> SetOfIterator<'static, S2CellId> {
+    let coverer = fixed_level_coverer(level, max_cells);
+    cells_to_setof(coverer.covering(&rect.to_rect()).0)
+}
+
+extension_sql!(
+    r#"
+CREATE FUNCTION s2_cover_cap_ranges(
+    cap s2cap,
+    level integer,
+    max_cells integer DEFAULT 8
+)
+RETURNS SETOF int8range
+IMMUTABLE PARALLEL SAFE
+LANGUAGE SQL
+AS $$
+    SELECT int8range(
+        s2_cell_to_bigint(s2_cell_range_min(cell)),
+        s2_cell_to_bigint(s2_cell_range_max(cell)),
+        '[]'
+    )
+    FROM s2_cover_cap($1, $2, $3) AS cell
+$$;
+
+CREATE FUNCTION s2_cover_rect_ranges(
+    rect s2latlngrect,
+    level integer,
+    max_cells integer DEFAULT 8
+)
+RETURNS SETOF int8range
+IMMUTABLE PARALLEL SAFE
+LANGUAGE SQL
+AS $$
+    SELECT int8range(
+        s2_cell_to_bigint(s2_cell_range_min(cell)),
+        s2_cell_to_bigint(s2_cell_range_max(cell)),
+        '[]'
+    )
+    FROM s2_cover_rect($1, $2, $3) AS cell
+$$;
+"#,
+    name = "s2_region_cover_ranges",
+    requires = [
+        s2_cover_s2cap,
+        s2_cover_s2latlngrect,
+        s2_cell_range_min,
+        s2_cell_range_max,
+        s2_cell_to_bigint
+    ],
+);
+
+// Point-set aggregates keep their transition state in float8[] so partial
+// states can be combined across parallel workers without serialization.
+
+fn cap_from_state(state: &[f64]) -> Cap {
+    match state {
+        [x, y, z, radius] => Cap::from_center_angle(
+            &S2Point(Vector {
+                x: *x,
+                y: *y,
+                z: *z,
+            }),
+            &Angle::from(Rad(*radius)),
+        ),
+        _ => Cap::empty(),
+    }
+}
+
+fn cap_state(cap: &Cap) -> Vec<f64> {
+    if cap.is_empty() {
+        return Vec::new();
+    }
+    let center = cap.center();
+    vec![center.0.x, center.0.y, center.0.z, cap.radius().rad()]
+}
+
+fn rect_from_state(state: &[f64]) -> Rect {
+    match state {
+        [lat_lo, lat_hi, lng_lo, lng_hi] => Rect {
+            lat: R1Interval::new(*lat_lo, *lat_hi),
+            lng: S1Interval::new(*lng_lo, *lng_hi),
+        },
+        _ => Rect::empty(),
+    }
+}
+
+fn rect_state(rect: &Rect) -> Vec<f64> {
+    if rect.is_empty() {
+        return Vec::new();
+    }
+    vec![rect.lat.lo, rect.lat.hi, rect.lng.lo, rect.lng.hi]
+}
+
+fn rect_add_latlng(state: &[f64], latlng: Point) -> Vec<f64> {
+    let ll = valid_latlng(latlng);
+    rect_state(&rect_from_state(state).union(&Rect::from_point_pair(&ll, &ll)))
+}
+
+/// Spherical centroid: the normalized mean of the points' unit vectors.
+#[derive(AggregateName)]
+#[aggregate_name = "s2_centroid_agg"]
+pub struct S2CentroidAgg;
+
+#[pg_aggregate]
+impl Aggregate<S2CentroidAgg> for S2CentroidAgg {
+    type State = Vec<f64>;
+    type Args = name!(latlng, Point);
+    type Finalize = Option<Point>;
+    const INITIAL_CONDITION: Option<&'static str> = Some("{0,0,0}");
+    const PARALLEL: Option<ParallelOption> = Some(ParallelOption::Safe);
+
+    #[pgrx(immutable, parallel_safe)]
+    fn state(
+        mut current: Self::State,
+        latlng: Self::Args,
+        _fcinfo: pg_sys::FunctionCallInfo,
+    ) -> Self::State {
+        let p = S2Point::from(valid_latlng(latlng));
+        current[0] += p.0.x;
+        current[1] += p.0.y;
+        current[2] += p.0.z;
+        current
+    }
+
+    #[pgrx(immutable, parallel_safe)]
+    fn combine(
+        mut current: Self::State,
+        other: Self::State,
+        _fcinfo: pg_sys::FunctionCallInfo,
+    ) -> Self::State {
+        for (sum, value) in current.iter_mut().zip(other) {
+            *sum += value;
+        }
+        current
+    }
+
+    #[pgrx(immutable, parallel_safe)]
+    fn finalize(
+        current: Self::State,
+        _direct_args: Self::OrderedSetArgs,
+        _fcinfo: pg_sys::FunctionCallInfo,
+    ) -> Self::Finalize {
+        let sum = Vector {
+            x: current[0],
+            y: current[1],
+            z: current[2],
+        };
+        // No rows, or points that cancel out (e.g. an antipodal pair).
+        if sum.norm() <= f64::EPSILON {
+            return None;
+        }
+        Some(latlng_to_point(&LatLng::from(S2Point(sum.normalize()))))
+    }
+}
+
+/// Cap enclosing every point, grown one point (or partial cap) at a time. It
+/// always contains the input but is not guaranteed to be the minimal cap.
+#[derive(AggregateName)]
+#[aggregate_name = "s2_bounding_cap_agg"]
+pub struct S2BoundingCapAgg;
+
+#[pg_aggregate]
+impl Aggregate<S2BoundingCapAgg> for S2BoundingCapAgg {
+    type State = Vec<f64>;
+    type Args = name!(latlng, Point);
+    type Finalize = Option<S2Cap>;
+    const INITIAL_CONDITION: Option<&'static str> = Some("{}");
+    const PARALLEL: Option<ParallelOption> = Some(ParallelOption::Safe);
+
+    #[pgrx(immutable, parallel_safe)]
+    fn state(
+        current: Self::State,
+        latlng: Self::Args,
+        _fcinfo: pg_sys::FunctionCallInfo,
+    ) -> Self::State {
+        let point = Cap::from_center_angle(
+            &S2Point::from(valid_latlng(latlng)),
+            &Angle::from(Rad(0.0)),
+        );
+        cap_state(&cap_union(&cap_from_state(&current), &point))
+    }
+
+    #[pgrx(immutable, parallel_safe)]
+    fn combine(
+        current: Self::State,
+        other: Self::State,
+        _fcinfo: pg_sys::FunctionCallInfo,
+    ) -> Self::State {
+        cap_state(&cap_union(&cap_from_state(&current), &cap_from_state(&other)))
+    }
+
+    #[pgrx(immutable, parallel_safe)]
+    fn finalize(
+        current: Self::State,
+        _direct_args: Self::OrderedSetArgs,
+        _fcinfo: pg_sys::FunctionCallInfo,
+    ) -> Self::Finalize {
+        let cap = cap_from_state(&current);
+        (!cap.is_empty()).then(|| S2Cap::from_cap(&cap))
+    }
+}
+
+/// Bounding box of the points. Longitudes use the shortest interval covering
+/// all points, so clusters straddling ±180° do not span the whole globe; such
+/// rects widen to -180..180 because a `box` cannot wrap (see
+/// `s2_latlngrect_agg` for the wrapped rect).
+#[derive(AggregateName)]
+#[aggregate_name = "s2_bounding_rect_agg"]
+pub struct S2BoundingRectAgg;
+
+#[pg_aggregate]
+impl Aggregate<S2BoundingRectAgg> for S2BoundingRectAgg {
+    type State = Vec<f64>;
+    type Args = name!(latlng, Point);
+    type Finalize = Option<BOX>;
+    const INITIAL_CONDITION: Option<&'static str> = Some("{}");
+    const PARALLEL: Option<ParallelOption> = Some(ParallelOption::Safe);
+
+    #[pgrx(immutable, parallel_safe)]
+    fn state(
+        current: Self::State,
+        latlng: Self::Args,
+        _fcinfo: pg_sys::FunctionCallInfo,
+    ) -> Self::State {
+        rect_add_latlng(&current, latlng)
+    }
+
+    #[pgrx(immutable, parallel_safe)]
+    fn combine(
+        current: Self::State,
+        other: Self::State,
+        _fcinfo: pg_sys::FunctionCallInfo,
+    ) -> Self::State {
+        rect_state(&rect_from_state(&current).union(&rect_from_state(&other)))
+    }
+
+    #[pgrx(immutable, parallel_safe)]
+    fn finalize(
+        current: Self::State,
+        _direct_args: Self::OrderedSetArgs,
+        _fcinfo: pg_sys::FunctionCallInfo,
+    ) -> Self::Finalize {
+        let rect = rect_from_state(&current);
+        (!rect.is_empty()).then(|| rect_to_box(&rect))
+    }
+}
+
+#[derive(AggregateName)]
+#[aggregate_name = "s2_latlngrect_agg"]
+pub struct S2LatLngRectAgg;
+
+#[pg_aggregate]
+impl Aggregate<S2LatLngRectAgg> for S2LatLngRectAgg {
+    type State = Vec<f64>;
+    type Args = name!(latlng, Point);
+    type Finalize = Option<S2LatLngRect>;
+    const INITIAL_CONDITION: Option<&'static str> = Some("{}");
+    const PARALLEL: Option<ParallelOption> = Some(ParallelOption::Safe);
+
+    #[pgrx(immutable, parallel_safe)]
+    fn state(
+        current: Self::State,
+        latlng: Self::Args,
+        _fcinfo: pg_sys::FunctionCallInfo,
+    ) -> Self::State {
+        rect_add_latlng(&current, latlng)
+    }
+
+    #[pgrx(immutable, parallel_safe)]
+    fn combine(
+        current: Self::State,
+        other: Self::State,
+        _fcinfo: pg_sys::FunctionCallInfo,
+    ) -> Self::State {
+        rect_state(&rect_from_state(&current).union(&rect_from_state(&other)))
+    }
+
+    #[pgrx(immutable, parallel_safe)]
+    fn finalize(
+        current: Self::State,
+        _direct_args: Self::OrderedSetArgs,
+        _fcinfo: pg_sys::FunctionCallInfo,
+    ) -> Self::Finalize {
+        let rect = rect_from_state(&current);
+        (!rect.is_empty()).then(|| S2LatLngRect::from_rect(&rect))
+    }
+}
+
+fn fixed_level_coverer(level: i32, max_cells: i32) -> RegionCoverer {
+    if !(0..=30).contains(&level) {
+        error!("invalid level");
+    }
+    if max_cells <= 0 {
+        error!("invalid max_cells");
+    }
+    RegionCoverer {
+        min_level: level as u8,
+        max_level: level as u8,
+        level_mod: 1,
+        max_cells: max_cells as usize,
+    }
+}
+
+/// Polygons with straight edges in lng/lat degrees, the planar model used by
+/// WKB, WKT and GeoJSON. Each polygon is an exterior ring followed by its
+/// holes; rings are stored open (without the repeated closing vertex).
+struct LngLatPolygons {
+    polygons: Vec<Vec<Vec<Point>>>,
+    bound: Rect,
+}
+
+impl LngLatPolygons {
+    fn new(mut polygons: Vec<Vec<Vec<Point>>>) -> Option<Self> {
+        for ring in polygons.iter_mut().flatten() {
+            let closed = match (ring.first(), ring.last()) {
+                (Some(first), Some(last)) => first.x == last.x && first.y == last.y,
+                _ => false,
+            };
+            if closed && ring.len() > 1 {
+                ring.pop();
+            }
+        }
+        let (mut lat_lo, mut lat_hi) = (f64::INFINITY, f64::NEG_INFINITY);
+        let (mut lng_lo, mut lng_hi) = (f64::INFINITY, f64::NEG_INFINITY);
+        for p in polygons.iter().flatten().flatten() {
+            if !(-90.0..=90.0).contains(&p.y) || !(-180.0..=180.0).contains(&p.x) {
+                return None;
+            }
+            lat_lo = lat_lo.min(p.y);
+            lat_hi = lat_hi.max(p.y);
+            lng_lo = lng_lo.min(p.x);
+            lng_hi = lng_hi.max(p.x);
+        }
+        if polygons.is_empty() || polygons.iter().flatten().any(|ring| ring.len() < 3) {
+            return None;
+        }
+        Some(LngLatPolygons {
+            polygons,
+            bound: Rect::from_degrees(lat_lo, lng_lo, lat_hi, lng_hi),
+        })
+    }
+
+    fn contains_point(&self, x: f64, y: f64) -> bool {
+        self.polygons.iter().any(|rings| {
+            rings
+                .iter()
+                .filter(|ring| ring_contains_point(ring, x, y))
+                .count()
+                % 2
+                == 1
+        })
+    }
+
+    fn edge_intersects_box(&self, lo: &Point, hi: &Point) -> bool {
+        self.polygons.iter().flatten().any(|ring| {
+            ring.iter()
+                .zip(ring.iter().cycle().skip(1))
+                .any(|(a, b)| segment_intersects_box(a, b, lo, hi))
+        })
+    }
+
+    fn contains_box(&self, lo: &Point, hi: &Point) -> bool {
+        !self.edge_intersects_box(lo, hi)
+            && self.contains_point((lo.x + hi.x) / 2.0, (lo.y + hi.y) / 2.0)
+    }
+
+    fn intersects_box(&self, lo: &Point, hi: &Point) -> bool {
+        self.edge_intersects_box(lo, hi)
+            || self.contains_point((lo.x + hi.x) / 2.0, (lo.y + hi.y) / 2.0)
+    }
+}
+
+/// Even-odd test of (`x`, `y`) against an open ring.
+fn ring_contains_point(ring: &[Point], x: f64, y: f64) -> bool {
+    let mut inside = false;
+    for (a, b) in ring.iter().zip(ring.iter().cycle().skip(1)) {
+        if (a.y > y) != (b.y > y) && x < a.x + (y - a.y) * (b.x - a.x) / (b.y - a.y) {
+            inside = !inside;
+        }
+    }
+    inside
+}
+
+/// Liang-Barsky test of segment `a`-`b` against the closed box `lo`-`hi`.
+fn segment_intersects_box(a: &Point, b: &Point, lo: &Point, hi: &Point) -> bool {
+    let (dx, dy) = (b.x - a.x, b.y - a.y);
+    let (mut t0, mut t1) = (0.0f64, 1.0f64);
+    for (p, q) in [
+        (-dx, a.x - lo.x),
+        (dx, hi.x - a.x),
+        (-dy, a.y - lo.y),
+        (dy, hi.y - a.y),
+    ] {
+        if p == 0.0 {
+            if q < 0.0 {
+                return false;
+            }
+            continue;
+        }
+        let r = q / p;
+        if p < 0.0 {
+            t0 = t0.max(r);
+        } else {
+            t1 = t1.min(r);
+        }
+        if t0 > t1 {
+            return false;
+        }
+    }
+    true
+}
+
+/// Lng/lat boxes bounding a cell; two boxes when the bound wraps ±180.
+fn cell_lng_lat_boxes(cell: &Cell) -> Vec<(Point, Point)> {
+    let rect = cell.rect_bound();
+    let (lat_lo, lat_hi) = (rect.lat_lo().deg(), rect.lat_hi().deg());
+    let (lng_lo, lng_hi) = (rect.lng_lo().deg(), rect.lng_hi().deg());
+    let lng_box = |lo: f64, hi: f64| (Point { x: lo, y: lat_lo }, Point { x: hi, y: lat_hi });
+    if rect.is_inverted() {
+        vec![lng_box(lng_lo, 180.0), lng_box(-180.0, lng_hi)]
+    } else {
+        vec![lng_box(lng_lo, lng_hi)]
+    }
+}
+
+impl Region for LngLatPolygons {
+    fn cap_bound(&self) -> Cap {
+        self.bound.cap_bound()
+    }
+
+    fn rect_bound(&self) -> Rect {
+        self.bound.clone()
+    }
+
+    fn contains_cell(&self, cell: &Cell) -> bool {
+        cell_lng_lat_boxes(cell)
+            .iter()
+            .all(|(lo, hi)| self.contains_box(lo, hi))
+    }
+
+    fn intersects_cell(&self, cell: &Cell) -> bool {
+        cell_lng_lat_boxes(cell)
+            .iter()
+            .any(|(lo, hi)| self.intersects_box(lo, hi))
+    }
+}
+
+struct WkbReader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+    little_endian: bool,
+}
+
+impl WkbReader<'_> {
+    fn take<const N: usize>(&mut self) -> Option<[u8; N]> {
+        let chunk = self.bytes.get(self.pos..self.pos + N)?;
+        self.pos += N;
+        chunk.try_into().ok()
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        let b = self.take::<4>()?;
+        Some(if self.little_endian {
+            u32::from_le_bytes(b)
+        } else {
+            u32::from_be_bytes(b)
+        })
+    }
+
+    fn f64(&mut self) -> Option<f64> {
+        let b = self.take::<8>()?;
+        Some(if self.little_endian {
+            f64::from_le_bytes(b)
+        } else {
+            f64::from_be_bytes(b)
+        })
+    }
+
+    /// Reads a geometry header (byte order, type, optional SRID) and returns
+    /// the base type with the number of ordinates per point.
+    fn header(&mut self) -> Option<(u32, usize)> {
+        self.little_endian = match self.take::<1>()?[0] {
+            0 => false,
+            1 => true,
+            _ => return None,
+        };
+        let raw_type = self.u32()?;
+        let mut dims = 2 + (raw_type >> 31) as usize + ((raw_type >> 30) & 1) as usize;
+        let iso_type = raw_type & 0x0fff_ffff;
+        dims += match iso_type / 1000 {
+            1 | 2 => 1,
+            3 => 2,
+            _ => 0,
+        };
+        if raw_type & EWKB_SRID_FLAG != 0 {
+            self.u32()?;
+        }
+        Some((iso_type % 1000, dims))
+    }
+
+    /// Reads the rings of a Polygon whose header has been consumed.
+    fn polygon(&mut self, dims: usize, out: &mut Vec<Vec<Vec<Point>>>) -> Option<()> {
+        let num_rings = self.u32()?;
+        let mut rings = Vec::new();
+        for _ in 0..num_rings {
+            let num_points = self.u32()? as usize;
+            let mut ring = Vec::with_capacity(num_points.min(self.bytes.len() / 16));
+            for _ in 0..num_points {
+                let x = self.f64()?;
+                let y = self.f64()?;
+                for _ in 2..dims {
+                    self.f64()?;
+                }
+                ring.push(Point { x, y });
+            }
+            rings.push(ring);
+        }
+        if !rings.is_empty() {
+            out.push(rings);
+        }
+        Some(())
+    }
+
+    /// Reads one (E)WKB Polygon or MultiPolygon, appending its polygons to
+    /// `out`. Z/M ordinates are skipped. MultiPolygon members must be
+    /// Polygons, so nesting is rejected rather than recursed into.
+    fn read_polygons(&mut self, out: &mut Vec<Vec<Vec<Point>>>) -> Option<()> {
+        match self.header()? {
+            (WKB_POLYGON, dims) => self.polygon(dims, out),
+            (WKB_MULTIPOLYGON, _) => {
+                let num_polygons = self.u32()?;
+                for _ in 0..num_polygons {
+                    match self.header()? {
+                        (WKB_POLYGON, dims) => self.polygon(dims, out)?,
+                        _ => return None,
+                    }
+                }
+                Some(())
+            }
+            _ => None,
+        }
+    }
+}
+
+fn parse_wkb_polygons(bytes: &[u8]) -> Option<LngLatPolygons> {
+    let mut reader = WkbReader {
+        bytes,
+        pos: 0,
+        little_endian: true,
+    };
+    let mut polygons = Vec::new();
+    reader.read_polygons(&mut polygons)?;
+    if reader.pos != bytes.len() {
+        return None;
+    }
+    LngLatPolygons::new(polygons)
+}
+
+/// Merges the leaf ranges of `cells` (ascending, as coverings are returned)
+/// into inclusive `i64_norm` bounds. Ranges of consecutive cells, which
+/// differ by one leaf position, are joined.
+fn merged_cell_ranges(cells: &[CellID]) -> Vec<(i64, i64)> {
+    let mut out: Vec<(i64, i64)> = Vec::with_capacity(cells.len());
+    for cell in cells {
+        let lo = u64_to_i64_norm(cell.range_min().0);
+        let hi = u64_to_i64_norm(cell.range_max().0);
+        match out.last_mut() {
+            Some(last) if lo <= last.1.saturating_add(2) => last.1 = last.1.max(hi),
+            _ => out.push((lo, hi)),
+        }
+    }
+    out
+}
+
+fn cover_polygons(
+    region: Option<LngLatPolygons>,
+    level: i32,
+    max_cells: i32,
+    what: &str,
+) -> Vec<CellID> {
+    let coverer = fixed_level_coverer(level, max_cells);
+    let Some(region) = region else {
+        error!("invalid polygon {}", what);
+    };
+    coverer.covering(&region).0
+}
+
+fn cells_to_setof(cells: Vec<CellID>) -> SetOfIterator<'static, S2CellId> {
+    SetOfIterator::new(cells.into_iter().map(|c| S2CellId::from_u64(c.0)))
+}
+
+fn cells_to_range_setof(cells: Vec<CellID>) -> SetOfIterator<'static, Range<i64>> {
+    SetOfIterator::new(cells_to_ranges(&cells))
+}
+
+fn cells_to_ranges(cells: &[CellID]) -> Vec<Range<i64>> {
+    merged_cell_ranges(cells)
+        .into_iter()
+        .map(|(lo, hi)| Range::from(lo..=hi))
+        .collect()
+}
+
+/// Merged `int8range[]` form of a cap covering. `s2_cover_cap_multirange` wraps it
+/// on PostgreSQL 14+; on 13 use it with `cell::bigint <@ ANY (...)`.
+#[pg_extern(stable, parallel_safe)]
+fn s2_cover_cap_range_array(
+    center: Point,
+    radius_m: f64,
+    level: i32,
+    max_cells: default!(i32, 8),
+) -> Vec<Range<i64>> {
+    let coverer = fixed_level_coverer(level, max_cells);
+    let cap = cap_from_center_radius(center, radius_m);
+    cells_to_ranges(&coverer.covering(&cap).0)
+}
+
+#[pg_extern(stable, parallel_safe)]
+fn s2_cover_rect_range_array(
+    rect: pg_sys::BOX,
+    level: i32,
+    max_cells: default!(i32, 8),
+) -> Vec<Range<i64>> {
+    let coverer = fixed_level_coverer(level, max_cells);
+    cells_to_ranges(&coverer.covering(&box_to_rect(&rect)).0)
+}
+
+#[cfg(not(feature = "pg13"))]
+extension_sql!(
+    r#"
+CREATE FUNCTION s2_cover_cap_multirange(
+    center point,
+    radius_m double precision,
+    level integer,
+    max_cells integer DEFAULT 8
+)
+RETURNS int8multirange
+STABLE PARALLEL SAFE
+LANGUAGE SQL
+AS $$
+    SELECT int8multirange(VARIADIC s2_cover_cap_range_array($1, $2, $3, $4))
+$$;
+
+CREATE FUNCTION s2_cover_rect_multirange(
+    rect box,
+    level integer,
+    max_cells integer DEFAULT 8
+)
+RETURNS int8multirange
+STABLE PARALLEL SAFE
+LANGUAGE SQL
+AS $$
+    SELECT int8multirange(VARIADIC s2_cover_rect_range_array($1, $2, $3))
+$$;
+"#,
+    name = "s2_cover_multirange",
+    requires = [s2_cover_cap_range_array, s2_cover_rect_range_array],
+);
+
+#[pg_extern(stable)]
+fn s2_cover_wkb(
+    wkb: &[u8],
+    level: i32,
+    max_cells: default!(i32, 8),
+) -> SetOfIterator<'static, S2CellId> {
+    cells_to_setof(cover_polygons(parse_wkb_polygons(wkb), level, max_cells, "wkb"))
+}
+
+#[pg_extern(stable, name = "s2_cover_wkb")]
+fn s2_cover_wkb_default(wkb: &[u8]) -> SetOfIterator<'static, S2CellId> {
+    let level = DEFAULT_COVER_LEVEL.get();
+    s2_cover_wkb(wkb, level, DEFAULT_MAX_CELLS)
+}
+
+/// Coordinates of a GeoJSON ring, `[[lng, lat], ...]`; extra ordinates are
+/// ignored.
+fn geojson_ring_points(value: &Value) -> Option<Vec<Point>> {
+    value
+        .as_array()?
+        .iter()
+        .map(|coord| {
+            let coord = coord.as_array()?;
+            Some(Point {
+                x: coord.first()?.as_f64()?,
+                y: coord.get(1)?.as_f64()?,
+            })
+        })
+        .collect()
+}
+
+fn geojson_polygon_rings(value: &Value) -> Option<Vec<Vec<Point>>> {
+    value.as_array()?.iter().map(geojson_ring_points).collect()
+}
+
+/// Accepts a Polygon or MultiPolygon geometry, or a Feature wrapping one.
+fn parse_geojson_polygons(value: &Value) -> Option<LngLatPolygons> {
+    let polygons = match value.get("type")?.as_str()? {
+        "Feature" => return parse_geojson_polygons(value.get("geometry")?),
+        "Polygon" => vec![geojson_polygon_rings(value.get("coordinates")?)?],
+        "MultiPolygon" => value
+            .get("coordinates")?
+            .as_array()?
+            .iter()
+            .map(geojson_polygon_rings)
+            .collect::<Option<Vec<_>>>()?,
+        _ => return None,
+    };
+    LngLatPolygons::new(polygons.into_iter().filter(|rings| !rings.is_empty()).collect())
+}
+
+struct WktReader<'a> {
+    input: &'a str,
+}
+
+impl WktReader<'_> {
+    fn skip_ws(&mut self) {
+        self.input = self.input.trim_start();
+    }
+
+    fn eat(&mut self, c: char) -> bool {
+        self.skip_ws();
+        match self.input.strip_prefix(c) {
+            Some(rest) => {
+                self.input = rest;
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn word(&mut self) -> String {
+        self.skip_ws();
+        let end = self
+            .input
+            .find(|c: char| !c.is_ascii_alphabetic())
+            .unwrap_or(self.input.len());
+        let (word, rest) = self.input.split_at(end);
+        self.input = rest;
+        word.to_ascii_uppercase()
+    }
+
+    fn number(&mut self) -> Option<f64> {
+        self.skip_ws();
+        let end = self
+            .input
+            .find(|c: char| !(c.is_ascii_digit() || matches!(c, '+' | '-' | '.' | 'e' | 'E')))
+            .unwrap_or(self.input.len());
+        let (number, rest) = self.input.split_at(end);
+        self.input = rest;
+        number.parse().ok()
+    }
+
+    /// `(x y [z [m]], ...)`
+    fn ring(&mut self) -> Option<Vec<Point>> {
+        if !self.eat('(') {
+            return None;
+        }
+        let mut ring = Vec::new();
+        loop {
+            let x = self.number()?;
+            let y = self.number()?;
+            self.skip_ws();
+            while self
+                .input
+                .starts_with(|c: char| c.is_ascii_digit() || matches!(c, '+' | '-' | '.'))
+            {
+                self.number()?;
+                self.skip_ws();
+            }
+            ring.push(Point { x, y });
+            if !self.eat(',') {
+                break;
+            }
+        }
+        self.eat(')').then_some(ring)
+    }
+
+    /// `(ring, ...)`, or `EMPTY` inside a MULTIPOLYGON.
+    fn polygon(&mut self) -> Option<Vec<Vec<Point>>> {
+        if !self.eat('(') {
+            return (self.word() == "EMPTY").then(Vec::new);
+        }
+        let mut rings = vec![self.ring()?];
+        while self.eat(',') {
+            rings.push(self.ring()?);
+        }
+        self.eat(')').then_some(rings)
+    }
+}
+
+/// Parses `POLYGON` / `MULTIPOLYGON` WKT, with an optional EWKT `SRID=...;`
+/// prefix and Z/M ordinates.
+fn parse_wkt_polygons(input: &str) -> Option<LngLatPolygons> {
+    let input = input.trim();
+    let input = match input.get(..5) {
+        Some(prefix) if prefix.eq_ignore_ascii_case("SRID=") => input.split_once(';')?.1,
+        _ => input,
+    };
+    let mut reader = WktReader { input };
+    let kind = reader.word();
+    if !matches!(reader.word().as_str(), "" | "Z" | "M" | "ZM") {
+        return None;
+    }
+    let mut polygons = Vec::new();
+    match kind.as_str() {
+        "POLYGON" => polygons.push(reader.polygon()?),
+        "MULTIPOLYGON" => {
+            if !reader.eat('(') {
+                return None;
+            }
+            polygons.push(reader.polygon()?);
+            while reader.eat(',') {
+                polygons.push(reader.polygon()?);
+            }
+            if !reader.eat(')') {
+                return None;
+            }
+        }
+        _ => return None,
+    }
+    reader.skip_ws();
+    if !reader.input.is_empty() {
+        return None;
+    }
+    LngLatPolygons::new(polygons.into_iter().filter(|rings| !rings.is_empty()).collect())
+}
+
+#[pg_extern(stable)]
+fn s2_cover_geojson(
+    geojson: Json,
+    level: i32,
+    max_cells: default!(i32, 8),
+) -> SetOfIterator<'static, S2CellId> {
+    let region = parse_geojson_polygons(&geojson.0);
+    cells_to_setof(cover_polygons(region, level, max_cells, "geojson"))
+}
+
+#[pg_extern(stable, name = "s2_cover_geojson")]
+fn s2_cover_geojson_default(geojson: Json) -> SetOfIterator<'static, S2CellId> {
+    let level = DEFAULT_COVER_LEVEL.get();
+    s2_cover_geojson(geojson, level, DEFAULT_MAX_CELLS)
+}
+
+#[pg_extern(stable)]
+fn s2_cover_geojson_ranges(
+    geojson: Json,
+    level: i32,
+    max_cells: default!(i32, 8),
+) -> SetOfIterator<'static, Range<i64>> {
+    let region = parse_geojson_polygons(&geojson.0);
+    cells_to_range_setof(cover_polygons(region, level, max_cells, "geojson"))
+}
+
+#[pg_extern(stable)]
+fn s2_cover_wkt(
+    wkt: &str,
+    level: i32,
+    max_cells: default!(i32, 8),
+) -> SetOfIterator<'static, S2CellId> {
+    cells_to_setof(cover_polygons(parse_wkt_polygons(wkt), level, max_cells, "wkt"))
+}
+
+#[pg_extern(stable, name = "s2_cover_wkt")]
+fn s2_cover_wkt_default(wkt: &str) -> SetOfIterator<'static, S2CellId> {
+    let level = DEFAULT_COVER_LEVEL.get();
+    s2_cover_wkt(wkt, level, DEFAULT_MAX_CELLS)
+}
+
+#[pg_extern(stable)]
+fn s2_cover_wkt_ranges(
+    wkt: &str,
+    level: i32,
+    max_cells: default!(i32, 8),
+) -> SetOfIterator<'static, Range<i64>> {
+    cells_to_range_setof(cover_polygons(parse_wkt_polygons(wkt), level, max_cells, "wkt"))
+}
+
+/// Parses the text form of a native `path` or `polygon`, e.g.
+/// `[(1,2),(3,4)]`. Returns the points and whether the value is closed
+/// (parenthesised rather than bracketed).
+fn parse_pg_points(input: &str) -> Option<(Vec<Point>, bool)> {
+    let input = input.trim();
+    let closed = input.starts_with('(');
+    let numbers = parse_numbers(input, &['(', ')', '[', ']', ','])?;
+    if numbers.is_empty() || numbers.len() % 2 != 0 {
+        return None;
+    }
+    let points = numbers
+        .chunks(2)
+        .map(|xy| Point { x: xy[0], y: xy[1] })
+        .collect();
+    Some((points, closed))
+}
+
+/// Polyline with geodesic edges, widened by `radius` (radians) on each side.
+struct BufferedPolyline {
+    vertices: Vec<S2Point>,
+    radius: f64,
+    bound: Cap,
+}
+
+impl BufferedPolyline {
+    fn new(points: &[Point], closed: bool, radius: f64) -> Option<Self> {
+        let mut vertices = points
+            .iter()
+            .map(|p| {
+                let ll = LatLng::from_degrees(p.y, p.x);
+                ll.is_valid().then(|| S2Point::from(ll))
+            })
+            .collect::<Option<Vec<_>>>()?;
+        if vertices.len() < 2 {
+            return None;
+        }
+        if closed && vertices.len() > 2 {
+            vertices.push(vertices[0]);
+        }
+        // Geodesic edges stay inside any cap smaller than a hemisphere that
+        // holds their endpoints.
+        let sum = vertices
+            .iter()
+            .fold(Vector::new(0.0, 0.0, 0.0), |acc, v| acc + v.0);
+        let bound = if sum.norm() < 1e-12 {
+            Cap::full()
+        } else {
+            let center = S2Point(sum.normalize());
+            let max_dist = vertices
+                .iter()
+                .map(|v| center.distance(v).rad())
+                .fold(0.0, f64::max);
+            if max_dist >= std::f64::consts::FRAC_PI_2 {
+                Cap::full()
+            } else {
+                let angle = (max_dist + radius).min(std::f64::consts::PI);
+                Cap::from_center_angle(&center, &Angle::from(Rad(angle)))
+            }
+        };
+        Some(BufferedPolyline {
+            vertices,
+            radius,
+            bound,
+        })
+    }
+}
+
+impl Region for BufferedPolyline {
+    fn cap_bound(&self) -> Cap {
+        self.bound.clone()
+    }
+
+    fn contains_cell(&self, _cell: &Cell) -> bool {
+        false
+    }
+
+    fn intersects_cell(&self, cell: &Cell) -> bool {
+        let cell_cap = cell.cap_bound();
+        let reach = cell_cap.radius().rad() + self.radius;
+        let corners = cell.vertices();
+        for (a, b) in self.vertices.iter().zip(self.vertices.iter().skip(1)) {
+            if distance_from_segment(cell_cap.center(), a, b).rad() > reach {
+                continue;
+            }
+            if cell.contains_point(a) || cell.contains_point(b) {
+                return true;
+            }
+            for k in 0..4 {
+                let (c, d) = (&corners[k], &corners[(k + 1) % 4]);
+                if simple_crossing(a, b, c, d)
+                    || distance_from_segment(a, c, d).rad() <= self.radius
+                    || distance_from_segment(b, c, d).rad() <= self.radius
+                    || distance_from_segment(c, a, b).rad() <= self.radius
+                {
+                    return true;
+                }
+            }
+        }
+        false
+    }
+}
+
+fn cover_polyline(path: &str, radius_m: f64, level: i32, max_cells: i32) -> Vec<CellID> {
+    let coverer = fixed_level_coverer(level, max_cells);
+    if radius_m < 0.0 {
+        error!("invalid radius");
+    }
+    let Some(polyline) = parse_pg_points(path).and_then(|(points, closed)| {
+        BufferedPolyline::new(&points, closed, meters_to_angle(radius_m).rad())
+    }) else {
+        error!("invalid path");
+    };
+    coverer.covering(&polyline).0
+}
+
+#[pg_extern(stable)]
+fn s2_cover_polyline_text(
+    path: &str,
+    radius_m: f64,
+    level: i32,
+    max_cells: i32,
+) -> SetOfIterator<'static, S2CellId> {
+    cells_to_setof(cover_polyline(path, radius_m, level, max_cells))
+}
+
+#[pg_extern(stable)]
+fn s2_cover_polyline_ranges_text(
+    path: &str,
+    radius_m: f64,
+    level: i32,
+    max_cells: i32,
+) -> SetOfIterator<'static, Range<i64>> {
+    cells_to_range_setof(cover_polyline(path, radius_m, level, max_cells))
+}
+
+extension_sql!(
+    r#"
+CREATE FUNCTION s2_cover_polyline(path path, level integer, max_cells integer DEFAULT 8)
+RETURNS SETOF s2cellid
+STABLE PARALLEL SAFE
+LANGUAGE SQL
+AS $$ SELECT s2_cover_polyline_text($1::text, 0, $2, $3) $$;
+
+CREATE FUNCTION s2_cover_polyline_ranges(path path, level integer, max_cells integer DEFAULT 8)
+RETURNS SETOF int8range
+STABLE PARALLEL SAFE
+LANGUAGE SQL
+AS $$ SELECT s2_cover_polyline_ranges_text($1::text, 0, $2, $3) $$;
+
+CREATE FUNCTION s2_cover_polyline_buffer(
+    path path,
+    radius_m double precision,
+    level integer,
+    max_cells integer DEFAULT 8
+)
+RETURNS SETOF s2cellid
+STABLE PARALLEL SAFE
+LANGUAGE SQL
+AS $$ SELECT s2_cover_polyline_text($1::text, $2, $3, $4) $$;
+
+CREATE FUNCTION s2_cover_polyline_buffer_ranges(
+    path path,
+    radius_m double precision,
+    level integer,
+    max_cells integer DEFAULT 8
+)
+RETURNS SETOF int8range
+STABLE PARALLEL SAFE
+LANGUAGE SQL
+AS $$ SELECT s2_cover_polyline_ranges_text($1::text, $2, $3, $4) $$;
+"#,
+    name = "s2_cover_polyline",
+    requires = [s2_cover_polyline_text, s2_cover_polyline_ranges_text],
+);
+
+extension_sql!(
+    r#"
+CREATE FUNCTION s2_cover_cap_ranges(
+    center point,
+    radius_m double precision,
+    level integer,
+    max_cells integer DEFAULT 8
+)
+RETURNS SETOF int8range
+STABLE PARALLEL SAFE
+LANGUAGE SQL
+AS $$
+    SELECT int8range(
+        s2_cell_to_bigint(s2_cell_range_min(cell)),
+        s2_cell_to_bigint(s2_cell_range_max(cell)),
+        '[]'
+    )
+    FROM s2_cover_cap($1, $2, $3, $4) AS cell
+$$;
+"#,
+    name = "s2_cover_cap_ranges",
+    requires = [s2_cover_cap, s2_cell_range_min, s2_cell_range_max, s2_cell_to_bigint],
+);
+
+extension_sql!(
+    r#"
+CREATE FUNCTION s2_cover_rect_ranges(
+    rect box,
+    level integer,
+    max_cells integer DEFAULT 8
+)
+RETURNS SETOF int8range
+STABLE PARALLEL SAFE
+LANGUAGE SQL
+AS $$
+    SELECT int8range(
+        s2_cell_to_bigint(s2_cell_range_min(cell)),
+        s2_cell_to_bigint(s2_cell_range_max(cell)),
+        '[]'
+    )
+    FROM s2_cover_rect($1, $2, $3) AS cell
+$$;
+"#,
+    name = "s2_cover_rect_ranges",
+    requires = [s2_cover_rect, s2_cell_range_min, s2_cell_range_max, s2_cell_to_bigint],
+);
+
+extension_sql!(
+    r#"
+CREATE FUNCTION s2_cell_to_boundary(cell s2cellid)
+RETURNS polygon
+IMMUTABLE PARALLEL SAFE
+LANGUAGE SQL
+AS $$ SELECT s2_cell_boundary_text($1)::polygon $$;
+"#,
+    name = "s2_cell_to_boundary",
+    requires = [s2_cell_boundary_text],
+);
+
+extension_sql!(
+    r#"
+CREATE FUNCTION s2_cell_to_boundary(
+    cell s2cellid,
+    segments integer,
+    antimeridian text DEFAULT 'none'
+)
+RETURNS polygon
+IMMUTABLE PARALLEL SAFE
+LANGUAGE SQL
+AS $$ SELECT s2_cell_boundary_text($1, $2, $3)::polygon $$;
+
+CREATE FUNCTION s2_cell_to_boundary_split(cell s2cellid, segments integer DEFAULT 1)
+RETURNS SETOF polygon
+IMMUTABLE PARALLEL SAFE
+LANGUAGE SQL
+AS $$ SELECT part::polygon FROM unnest(s2_cell_boundary_split_text($1, $2)) AS part $$;
+"#,
+    name = "s2_cell_to_boundary_segments",
+    requires = [s2_cell_boundary_text_segments, s2_cell_boundary_split_text],
+);
+
+#[pg_extern(immutable)]
+fn s2_cell_range_min(cell: S2CellId) -> S2CellId {
+    let raw = cell.to_u64();
+    if !s2_cellid_is_valid_raw(raw) {
+        error!("invalid s2cellid");
+    }
+    let min = CellID(raw).range_min();
+    S2CellId::from_u64(min.0)
+}
+
+#[pg_extern(immutable)]
+fn s2_cell_range_max(cell: S2CellId) -> S2CellId {
+    let raw = cell.to_u64();
+    if !s2_cellid_is_valid_raw(raw) {
+        error!("invalid s2cellid");
+    }
+    let max = CellID(raw).range_max();
+    S2CellId::from_u64(max.0)
+}
+
+/// `SUBTYPE_DIFF` for `s2cellrange`: the gap between two ids in their sort order.
+#[pg_extern(immutable, parallel_safe)]
+fn s2cellid_subdiff(a: S2CellId, b: S2CellId) -> f64 {
+    a.value as f64 - b.value as f64
+}
+
+// Runs last so the btree operator class derived for s2cellid already exists.
+extension_sql!(
+    r#"
+CREATE TYPE s2cellrange AS RANGE (
+    SUBTYPE = s2cellid,
+    SUBTYPE_OPCLASS = s2cellid_btree_ops,
+    SUBTYPE_DIFF = s2cellid_subdiff
+);
+
+CREATE FUNCTION s2_cell_to_range(cell s2cellid)
+RETURNS s2cellrange
+IMMUTABLE STRICT PARALLEL SAFE
+LANGUAGE SQL
+AS $$
+    SELECT s2cellrange(s2_cell_range_min(cell), s2_cell_range_max(cell), '[]')
+$$;
+"#,
+    name = "s2cellrange",
+    requires = [s2cellid_subdiff, s2_cell_range_min, s2_cell_range_max],
+    finalize,
+);
+
+#[pg_extern(immutable)]
+fn s2_cell_to_parent(cell: S2CellId, level: i32) -> S2CellId {
+    if !(0..=30).contains(&level) {
+        error!("invalid level");
+    }
+    let raw = cell.to_u64();
+    if !s2_cellid_is_valid_raw(raw) {
+        error!("invalid s2cellid");
+    }
+    let cellid = CellID(raw);
+    let cell_level = cellid.level() as i32;
+    if level > cell_level {
+        error!("invalid level");
+    }
+    let parent = cellid.parent(level as u64);
+    S2CellId::from_u64(parent.0)
+}
+
+#[pg_extern(immutable, name = "s2_cell_to_parent")]
+fn s2_cell_to_parent_default(cell: S2CellId) -> S2CellId {
+    let raw = cell.to_u64();
+    if !s2_cellid_is_valid_raw(raw) {
+        error!("invalid s2cellid");
+    }
+    let cellid = CellID(raw);
+    let level = cellid.level();
+    if level == 0 {
+        error!("invalid level");
+    }
+    s2_cell_to_parent(cell, level as i32 - 1)
+}
+
+#[pg_extern(immutable)]
+fn s2_cells_to_parent(cells: Vec<Option<S2CellId>>, level: i32) -> Vec<Option<S2CellId>> {
+    if !(0..=30).contains(&level) {
+        error!("invalid level");
+    }
+    cells
+        .into_iter()
+        .map(|cell| cell.map(|c| s2_cell_to_parent(c, level)))
+        .collect()
+}
+
+// s2_cell_histogram and s2_cell_histogram_rollup share an Internal state of
+// counts keyed by (level, ancestor id). The generated pgrx deserialize
+// function does not have the (bytea, internal) signature PostgreSQL requires,
+// so the support functions and aggregates are declared by hand below, with
+// serialize/deserialize/combine so both can run in parallel.
+type CellCounts = BTreeMap<(u64, u64), i64>;
+
+/// Serialized state entry: level byte, ancestor id, count.
+const CELL_COUNT_ENTRY_BYTES: usize = 17;
+
+/// Returns the counts behind `state`, allocating them in the aggregate memory
+/// context on the first call so they outlive the current row.
+unsafe fn cell_counts_mut(
+    state: &mut Internal,
+    fcinfo: pg_sys::FunctionCallInfo,
+) -> &mut CellCounts {
+    if !state.initialized() {
+        let mut context = std::ptr::null_mut();
+        if pg_sys::AggCheckCallContext(fcinfo, &mut context) == 0 {
+            error!("s2 cell histogram state called outside an aggregate");
+        }
+        PgMemoryContexts::For(context).switch_to(|_| {
+            state.insert(CellCounts::new());
+        });
+    }
+    state.get_mut::<CellCounts>().expect("initialized state")
+}
+
+#[pg_extern(immutable, parallel_safe)]
+fn s2_cell_histogram_state(
+    mut state: Internal,
+    cell: Option<S2CellId>,
+    level: i32,
+    fcinfo: pg_sys::FunctionCallInfo,
+) -> Internal {
+    let counts = unsafe { cell_counts_mut(&mut state, fcinfo) };
+    if let Some(cell) = cell {
+        let parent = s2_cell_to_parent(cell, level).to_u64();
+        *counts.entry((level as u64, parent)).or_default() += 1;
+    }
+    state
+}
+
+#[pg_extern(immutable, parallel_safe)]
+fn s2_cell_histogram_rollup_state(
+    mut state: Internal,
+    cell: Option<S2CellId>,
+    levels: Vec<i32>,
+    fcinfo: pg_sys::FunctionCallInfo,
+) -> Internal {
+    if levels.iter().any(|level| !(0..=30).contains(level)) {
+        error!("invalid level");
+    }
+    let counts = unsafe { cell_counts_mut(&mut state, fcinfo) };
+    if let Some(cell) = cell {
+        let raw = cell.to_u64();
+        if !s2_cellid_is_valid_raw(raw) {
+            error!("invalid s2cellid");
+        }
+        let cellid = CellID(raw);
+        let levels: BTreeSet<u64> = levels.into_iter().map(|level| level as u64).collect();
+        for level in levels {
+            if level > cellid.level() {
+                error!("invalid level");
+            }
+            *counts.entry((level, cellid.parent(level).0)).or_default() += 1;
+        }
+    }
+    state
+}
+
+#[pg_extern(immutable, parallel_safe)]
+fn s2_cell_histogram_combine(
+    mut state: Internal,
+    other: Internal,
+    fcinfo: pg_sys::FunctionCallInfo,
+) -> Internal {
+    let counts = unsafe { cell_counts_mut(&mut state, fcinfo) };
+    if let Some(other) = unsafe { other.get::<CellCounts>() } {
+        for (&key, &count) in other {
+            *counts.entry(key).or_default() += count;
+        }
+    }
+    state
+}
+
+#[pg_extern(immutable, parallel_safe)]
+fn s2_cell_histogram_serialize(state: Internal) -> Vec<u8> {
+    let counts = unsafe { state.get::<CellCounts>() };
+    let mut out = Vec::new();
+    for (&(level, raw), &count) in counts.into_iter().flatten() {
+        out.push(level as u8);
+        out.extend_from_slice(&raw.to_le_bytes());
+        out.extend_from_slice(&count.to_le_bytes());
+    }
+    out
+}
+
+#[pg_extern(immutable, parallel_safe)]
+fn s2_cell_histogram_deserialize(bytes: Option<&[u8]>, _state: Internal) -> Internal {
+    let bytes = bytes.unwrap_or_default();
+    if bytes.len() % CELL_COUNT_ENTRY_BYTES != 0 {
+        error!("invalid s2 cell histogram state");
+    }
+    let counts: CellCounts = bytes
+        .chunks_exact(CELL_COUNT_ENTRY_BYTES)
+        .map(|entry| {
+            let raw = u64::from_le_bytes(entry[1..9].try_into().unwrap());
+            let count = i64::from_le_bytes(entry[9..].try_into().unwrap());
+            ((u64::from(entry[0]), raw), count)
+        })
+        .collect();
+    // Deserialized states only live until they are combined into the
+    // group's state, so the current (per-call) context is enough.
+    let mut state = Internal::default();
+    unsafe { state.insert(counts) };
+    state
+}
+
+fn cell_counts_object<'a>(
+    counts: impl Iterator<Item = (&'a (u64, u64), &'a i64)>,
+) -> serde_json::Map<String, Value> {
+    counts
+        .map(|(&(_, raw), &count)| (CellID(raw).to_token(), json!(count)))
+        .collect()
+}
+
+#[pg_extern(immutable, parallel_safe)]
+fn s2_cell_histogram_final(state: Internal) -> Option<JsonB> {
+    let counts = unsafe { state.get::<CellCounts>() }?;
+    Some(JsonB(Value::Object(cell_counts_object(counts.iter()))))
+}
+
+#[pg_extern(immutable, parallel_safe)]
+fn s2_cell_histogram_rollup_final(state: Internal) -> Option<JsonB> {
+    let counts = unsafe { state.get::<CellCounts>() }?;
+    let levels: BTreeSet<u64> = counts.keys().map(|&(level, _)| level).collect();
+    let object = levels
+        .into_iter()
+        .map(|level| {
+            let at_level = counts.range((level, 0)..=(level, u64::MAX));
+            (level.to_string(), Value::Object(cell_counts_object(at_level)))
+        })
+        .collect::<serde_json::Map<String, Value>>();
+    Some(JsonB(Value::Object(object)))
+}
+
+extension_sql!(
+    r#"
+-- Counts of cells grouped by their ancestor at `level`, as a jsonb object
+-- keyed by token.
+CREATE AGGREGATE s2_cell_histogram(cell s2cellid, level integer) (
+    SFUNC = s2_cell_histogram_state,
+    STYPE = internal,
+    FINALFUNC = s2_cell_histogram_final,
+    COMBINEFUNC = s2_cell_histogram_combine,
+    SERIALFUNC = s2_cell_histogram_serialize,
+    DESERIALFUNC = s2_cell_histogram_deserialize,
+    PARALLEL = SAFE
+);
+
+-- Cell counts at several levels in one pass, as {"level": {token: count}}.
+-- NULL cells are skipped; every cell must be at least as fine as the finest
+-- level.
+CREATE AGGREGATE s2_cell_histogram_rollup(cell s2cellid, levels integer[]) (
+    SFUNC = s2_cell_histogram_rollup_state,
+    STYPE = internal,
+    FINALFUNC = s2_cell_histogram_rollup_final,
+    COMBINEFUNC = s2_cell_histogram_combine,
+    SERIALFUNC = s2_cell_histogram_serialize,
+    DESERIALFUNC = s2_cell_histogram_deserialize,
+    PARALLEL = SAFE
+);
+"#,
+    name = "s2_cell_histogram_aggregates",
+    requires = [
+        s2_cell_histogram_state,
+        s2_cell_histogram_rollup_state,
+        s2_cell_histogram_combine,
+        s2_cell_histogram_serialize,
+        s2_cell_histogram_deserialize,
+        s2_cell_histogram_final,
+        s2_cell_histogram_rollup_final
+    ],
+);
+
+/// Normalized, sorted set of cells. The varlena payload is a version byte and
+/// a cell count, one restart entry (absolute id, offset into the delta stream)
+/// per block of `S2CELLUNION_BLOCK` cells, then varint deltas for the other
+/// cells of each block. Lookups binary-search the restarts and decode a single
+/// block. Values read from a datum borrow the detoasted varlena rather than
+/// copying it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct S2CellUnion<'a> {
+    bytes: Cow<'a, [u8]>,
+}
+
+fn push_varint(out: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        out.push((value as u8) | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+fn read_varint(bytes: &[u8], pos: &mut usize) -> Option<u64> {
+    let mut value = 0u64;
+    for shift in (0..64).step_by(7) {
+        let byte = *bytes.get(*pos)?;
+        *pos += 1;
+        value |= u64::from(byte & 0x7f) << shift;
+        if byte & 0x80 == 0 {
+            return Some(value);
+        }
+    }
+    None
+}
+
+fn read_u32(bytes: &[u8], pos: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(bytes.get(pos..pos + 4)?.try_into().ok()?))
+}
+
+fn read_u64(bytes: &[u8], pos: usize) -> Option<u64> {
+    Some(u64::from_le_bytes(bytes.get(pos..pos + 8)?.try_into().ok()?))
+}
+
+impl<'a> S2CellUnion<'a> {
+    const HEADER: usize = 5;
+    const RESTART: usize = 12;
+
+    fn from_cells(cells: Vec<CellID>) -> S2CellUnion<'static> {
+        let mut union = CellUnion(cells);
+        union.normalize();
+        let cells = union.0;
+        let blocks = cells.len().div_ceil(S2CELLUNION_BLOCK);
+        let capacity = Self::HEADER + blocks * Self::RESTART + cells.len() * 2;
+        let mut bytes = Vec::with_capacity(capacity);
+        bytes.push(S2CELLUNION_VERSION);
+        bytes.extend_from_slice(&(cells.len() as u32).to_le_bytes());
+        let mut stream = Vec::new();
+        for block in cells.chunks(S2CELLUNION_BLOCK) {
+            bytes.extend_from_slice(&block[0].0.to_le_bytes());
+            bytes.extend_from_slice(&(stream.len() as u32).to_le_bytes());
+            for pair in block.windows(2) {
+                push_varint(&mut stream, pair[1].0 - pair[0].0);
+            }
+        }
+        bytes.extend_from_slice(&stream);
+        S2CellUnion {
+            bytes: Cow::Owned(bytes),
+        }
+    }
+
+    fn from_datum_bytes(bytes: &'a [u8]) -> Self {
+        let union = S2CellUnion {
+            bytes: Cow::Borrowed(bytes),
+        };
+        if bytes.first() != Some(&S2CELLUNION_VERSION)
+            || bytes.len() < Self::HEADER
+            || bytes.len() < union.stream_start()
+        {
+            error!("invalid s2cellunion");
+        }
+        union
+    }
+
+    fn len(&self) -> usize {
+        read_u32(&self.bytes, 1).unwrap_or(0) as usize
+    }
+
+    fn blocks(&self) -> usize {
+        self.len().div_ceil(S2CELLUNION_BLOCK)
+    }
+
+    fn stream_start(&self) -> usize {
+        Self::HEADER + self.blocks() * Self::RESTART
+    }
+
+    fn restart(&self, block: usize) -> Option<(u64, usize)> {
+        let pos = Self::HEADER + block * Self::RESTART;
+        Some((read_u64(&self.bytes, pos)?, read_u32(&self.bytes, pos + 8)? as usize))
+    }
+
+    fn decode_block(&self, block: usize) -> Option<Vec<CellID>> {
+        let (first, offset) = self.restart(block)?;
+        let size = (self.len() - block * S2CELLUNION_BLOCK).min(S2CELLUNION_BLOCK);
+        let mut pos = self.stream_start() + offset;
+        let mut cells = Vec::with_capacity(size);
+        let mut current = first;
+        cells.push(CellID(current));
+        for _ in 1..size {
+            current = current.checked_add(read_varint(&self.bytes, &mut pos)?)?;
+            cells.push(CellID(current));
+        }
+        Some(cells)
+    }
+
+    fn try_cells(&self) -> Option<Vec<CellID>> {
+        if self.bytes.first() != Some(&S2CELLUNION_VERSION) || self.bytes.len() < Self::HEADER {
+            return None;
+        }
+        let mut cells = Vec::with_capacity(self.len());
+        for block in 0..self.blocks() {
+            cells.extend(self.decode_block(block)?);
+        }
+        Some(cells)
+    }
+
+    fn cells(&self) -> Vec<CellID> {
+        self.try_cells().unwrap_or_else(|| error!("invalid s2cellunion"))
+    }
+
+    /// Cells adjacent to `id` in sort order: the last one <= id and the first
+    /// one > id. Only the block holding them is decoded.
+    fn neighbors(&self, id: CellID) -> (Option<CellID>, Option<CellID>) {
+        let blocks = self.blocks();
+        // Number of blocks whose first cell is <= id.
+        let (mut lo, mut hi) = (0, blocks);
+        while lo < hi {
+            let mid = (lo + hi) / 2;
+            match self.restart(mid) {
+                Some((first, _)) if first <= id.0 => lo = mid + 1,
+                _ => hi = mid,
+            }
+        }
+        if lo == 0 {
+            return (None, self.restart(0).map(|(first, _)| CellID(first)));
+        }
+        let cells = self
+            .decode_block(lo - 1)
+            .unwrap_or_else(|| error!("invalid s2cellunion"));
+        let after = cells.partition_point(|cell| cell.0 <= id.0);
+        let next = cells
+            .get(after)
+            .copied()
+            .or_else(|| self.restart(lo).map(|(first, _)| CellID(first)));
+        (after.checked_sub(1).map(|i| cells[i]), next)
+    }
+
+    fn contains_cell(&self, id: CellID) -> bool {
+        match self.neighbors(id) {
+            (Some(prev), _) if prev.range_max().0 >= id.0 => true,
+            (_, Some(next)) => next.range_min().0 <= id.0,
+            _ => false,
+        }
+    }
+
+    fn intersects_cell(&self, id: CellID) -> bool {
+        let (prev, next) = self.neighbors(id);
+        prev.is_some_and(|prev| prev.range_max() >= id.range_min())
+            || next.is_some_and(|next| next.range_min() <= id.range_max())
+    }
+}
+
+unsafe impl SqlTranslatable for S2CellUnion<'_> {
+    fn argument_sql() -> Result<SqlMapping, ArgumentError> {
+        Ok(SqlMapping::As("s2cellunion".into()))
+    }
+
+    fn return_sql() -> Result<Returns, ReturnsError> {
+        Ok(Returns::One(SqlMapping::As("s2cellunion".into())))
+    }
+}
+
+impl<'a> FromDatum for S2CellUnion<'a> {
+    unsafe fn from_polymorphic_datum(
+        datum: pg_sys::Datum,
+        is_null: bool,
+        typoid: Oid,
+    ) -> Option<Self>
+    where
+        Self: Sized,
+    {
+        let bytes = <&'a [u8]>::from_polymorphic_datum(datum, is_null, typoid)?;
+        Some(S2CellUnion::from_datum_bytes(bytes))
+    }
+}
+
+impl IntoDatum for S2CellUnion<'_> {
+    fn into_datum(self) -> Option<pg_sys::Datum> {
+        self.bytes.as_ref().into_datum()
+    }
+
+    fn type_oid() -> Oid {
+        rust_regtypein::<Self>()
+    }
+}
+
+unsafe impl<'fcx> ArgAbi<'fcx> for S2CellUnion<'fcx>
+where
+    Self: 'fcx,
+{
+    unsafe fn unbox_arg_unchecked(arg: ::pgrx::callconv::Arg<'_, 'fcx>) -> Self {
+        arg.unbox_arg_using_from_datum().unwrap()
+    }
+}
+
+unsafe impl BoxRet for S2CellUnion<'_> {
+    unsafe fn box_into<'fcx>(self, fcinfo: &mut pgrx::callconv::FcInfo<'fcx>) -> Datum<'fcx> {
+        match self.into_datum() {
+            Some(datum) => fcinfo.return_raw_datum(datum),
+            None => fcinfo.return_null(),
+        }
+    }
+}
+
+#[pg_extern(immutable, parallel_safe, requires = ["s2cellunion_shell_type"])]
+fn s2cellunion_in(input: &CStr) -> S2CellUnion<'static> {
+    let text = input
+        .to_str()
+        .unwrap_or_else(|_| error!("invalid s2cellunion"));
+    let text = text.trim();
+    let body = text
+        .strip_prefix('{')
+        .and_then(|rest| rest.strip_suffix('}'))
+        .unwrap_or(text);
+    let cells = body
+        .split(',')
+        .map(str::trim)
+        .filter(|token| !token.is_empty())
+        .map(|token| {
+            let cellid = CellID::from_token(token);
+            if !s2_cellid_is_valid_raw(cellid.0) {
+                error!("invalid s2cellunion token");
+            }
+            cellid
+        })
+        .collect();
+    S2CellUnion::from_cells(cells)
+}
+
+#[pg_extern(immutable, parallel_safe, requires = ["s2cellunion_shell_type"])]
+fn s2cellunion_out(value: S2CellUnion<'_>) -> &'static CStr {
+    let tokens: Vec<String> = value.cells().iter().map(CellID::to_token).collect();
+    let mut s = StringInfo::new();
+    s.push_str(&format!("{{{}}}", tokens.join(",")));
+    unsafe { s.leak_cstr() }
+}
+
+extension_sql!(
+    r#"
+CREATE TYPE s2cellunion;
+"#,
+    name = "s2cellunion_shell_type",
+    requires = ["concrete_type"],
+);
+
+extension_sql!(
+    r#"
+CREATE TYPE s2cellunion (
+    INPUT = s2cellunion_in,
+    OUTPUT = s2cellunion_out,
+    INTERNALLENGTH = VARIABLE,
+    ALIGNMENT = int4,
+    STORAGE = extended
+);
+"#,
+    name = "s2cellunion_type",
+    creates = [Type(S2CellUnion)],
+    requires = ["s2cellunion_shell_type", s2cellunion_in, s2cellunion_out],
+);
+
+/// Builds a normalized union; NULL elements are skipped.
+#[pg_extern(immutable)]
+fn s2_cellunion(cells: Vec<Option<S2CellId>>) -> S2CellUnion<'static> {
+    S2CellUnion::from_cells(valid_cellids(cells))
+}
+
+fn valid_cellids(cells: Vec<Option<S2CellId>>) -> Vec<CellID> {
+    cells
+        .into_iter()
+        .flatten()
+        .map(|cell| {
+            let raw = cell.to_u64();
+            if !s2_cellid_is_valid_raw(raw) {
+                error!("invalid s2cellid");
+            }
+            CellID(raw)
+        })
+        .collect()
+}
+
+#[pg_extern(immutable)]
+fn s2_cellunion_cells(cells: S2CellUnion<'_>) -> Vec<S2CellId> {
+    cells
+        .cells()
+        .into_iter()
+        .map(|cellid| S2CellId::from_u64(cellid.0))
+        .collect()
+}
+
+#[pg_extern(immutable)]
+fn s2_cellunion_num_cells(cells: S2CellUnion<'_>) -> i32 {
+    cells.len() as i32
+}
+
+/// True when `cell` lies entirely inside the union.
+#[pg_extern(immutable)]
+fn s2_cellunion_contains(cells: S2CellUnion<'_>, cell: S2CellId) -> bool {
+    let raw = cell.to_u64();
+    if !s2_cellid_is_valid_raw(raw) {
+        error!("invalid s2cellid");
+    }
+    cells.contains_cell(CellID(raw))
+}
+
+#[pg_extern(immutable)]
+fn s2_cellunion_intersects(cells: S2CellUnion<'_>, cell: S2CellId) -> bool {
+    let raw = cell.to_u64();
+    if !s2_cellid_is_valid_raw(raw) {
+        error!("invalid s2cellid");
+    }
+    cells.intersects_cell(CellID(raw))
+}
+
+/// True when `cell` lies entirely inside the union; `cells @> cell` commuted.
+#[pg_extern(immutable)]
+fn s2_cell_within_cellunion(cell: S2CellId, cells: S2CellUnion<'_>) -> bool {
+    s2_cellunion_contains(cells, cell)
+}
+
+#[pg_extern(immutable, name = "s2_cellunion_intersects")]
+fn s2_cell_intersects_cellunion(cell: S2CellId, cells: S2CellUnion<'_>) -> bool {
+    s2_cellunion_intersects(cells, cell)
+}
+
+extension_sql!(
+    r#"
+CREATE CAST (s2cellid[] AS s2cellunion) WITH FUNCTION s2_cellunion(s2cellid[]);
+CREATE CAST (s2cellunion AS s2cellid[]) WITH FUNCTION s2_cellunion_cells(s2cellunion);
+
+CREATE OPERATOR @> (
+    LEFTARG = s2cellunion,
+    RIGHTARG = s2cellid,
+    FUNCTION = s2_cellunion_contains,
+    COMMUTATOR = <@,
+    RESTRICT = contsel,
+    JOIN = contjoinsel
+);
+
+CREATE OPERATOR <@ (
+    LEFTARG = s2cellid,
+    RIGHTARG = s2cellunion,
+    FUNCTION = s2_cell_within_cellunion,
+    COMMUTATOR = @>,
+    RESTRICT = contsel,
+    JOIN = contjoinsel
+);
+
+CREATE OPERATOR && (
+    LEFTARG = s2cellunion,
+    RIGHTARG = s2cellid,
+    FUNCTION = s2_cellunion_intersects,
+    COMMUTATOR = &&,
+    RESTRICT = contsel,
+    JOIN = contjoinsel
+);
+
+CREATE OPERATOR && (
+    LEFTARG = s2cellid,
+    RIGHTARG = s2cellunion,
+    FUNCTION = s2_cellunion_intersects,
+    COMMUTATOR = &&,
+    RESTRICT = contsel,
+    JOIN = contjoinsel
+);
+
+CREATE OPERATOR @> (
+    LEFTARG = s2cellunion,
+    RIGHTARG = s2cellunion,
+    FUNCTION = s2_cellunion_contains,
+    RESTRICT = contsel,
+    JOIN = contjoinsel
+);
+"#,
+    name = "s2cellunion_casts_operators",
+    requires = [
+        "s2cellunion_type",
+        s2_cellunion,
+        s2_cellunion_cells,
+        s2_cellunion_contains,
+        s2_cellunion_intersects,
+        s2_cell_within_cellunion,
+        s2_cell_intersects_cellunion,
+        s2_cellunion_contains_union
+    ],
+);
+
+/// True when some cell of `a` contains, equals or lies inside some cell of `b`.
+#[pg_extern(immutable, parallel_safe)]
+fn s2_cells_intersect(a: Vec<Option<S2CellId>>, b: Vec<Option<S2CellId>>) -> bool {
+    let (a, b) = (valid_cellids(a), valid_cellids(b));
+    let (small, large) = if a.len() <= b.len() { (a, b) } else { (b, a) };
+    let mut large = CellUnion(large);
+    large.normalize();
+    small.iter().any(|cellid| large.intersects_cellid(cellid))
+}
+
+#[pg_extern(immutable, parallel_safe, name = "s2_cellunion_intersects")]
+fn s2_cellunion_intersects_union(a: S2CellUnion<'_>, b: S2CellUnion<'_>) -> bool {
+    let (small, large) = if a.len() <= b.len() { (a, b) } else { (b, a) };
+    small.cells().into_iter().any(|cellid| large.intersects_cell(cellid))
+}
+
+/// True when every cell of `b` lies inside `a`.
+#[pg_extern(immutable, parallel_safe, name = "s2_cellunion_contains")]
+fn s2_cellunion_contains_union(a: S2CellUnion<'_>, b: S2CellUnion<'_>) -> bool {
+    b.cells().into_iter().all(|cellid| a.contains_cell(cellid))
+}
+
+// GIN keys for hierarchical `&&`. An indexed cell emits its own id plus an
+// "ancestor key" for every coarser cell above it; a query cell looks up its own
+// id, the ids of its ancestors and its own ancestor key. Two cells intersect
+// exactly when one of those pairs matches, so no recheck is needed.
+//
+// The ancestor key adds half the lowest set bit to the id. Valid ids have their
+// lowest set bit at an even position, so the tagged key never collides with one.
+fn gin_ancestor_key(cellid: CellID) -> i64 {
+    u64_to_i64_norm(cellid.0 + (cellid.lsb() >> 1))
+}
+
+fn gin_value_keys(cells: &[CellID]) -> Vec<i64> {
+    let mut keys = BTreeSet::new();
+    for cellid in cells {
+        keys.insert(u64_to_i64_norm(cellid.0));
+        for level in 0..cellid.level() {
+            keys.insert(gin_ancestor_key(cellid.parent(level)));
+        }
+    }
+    keys.into_iter().collect()
+}
+
+fn gin_query_keys(cells: &[CellID]) -> Vec<i64> {
+    let mut keys = BTreeSet::new();
+    for cellid in cells {
+        keys.insert(u64_to_i64_norm(cellid.0));
+        for level in 0..cellid.level() {
+            keys.insert(u64_to_i64_norm(cellid.parent(level).0));
+        }
+        if !cellid.is_leaf() {
+            keys.insert(gin_ancestor_key(*cellid));
+        }
+    }
+    keys.into_iter().collect()
+}
+
+/// Hands `keys` back to GIN as a palloc'd Datum array and stores its length in `nkeys`.
+fn gin_keys_datum(keys: Vec<i64>, mut nkeys: Internal) -> Internal {
+    unsafe {
+        let out = pg_sys::palloc(std::mem::size_of::<pg_sys::Datum>() * keys.len().max(1))
+            as *mut pg_sys::Datum;
+        for (i, key) in keys.iter().enumerate() {
+            *out.add(i) = pg_sys::Datum::from(*key);
+        }
+        if let Some(n) = nkeys.get_mut::<i32>() {
+            *n = keys.len() as i32;
+        }
+        Internal::from(Some(pg_sys::Datum::from(out)))
+    }
+}
+
+fn gin_any_key_matches(check: Internal, nkeys: i32, mut recheck: Internal) -> bool {
+    unsafe {
+        if let Some(recheck) = recheck.get_mut::<bool>() {
+            *recheck = false;
+        }
+        match check.unwrap() {
+            Some(check) if nkeys > 0 => {
+                std::slice::from_raw_parts(check.cast_mut_ptr::<bool>(), nkeys as usize)
+                    .iter()
+                    .any(|&matched| matched)
+            }
+            _ => false,
+        }
+    }
+}
+
+#[pg_extern(immutable, parallel_safe)]
+fn s2cellid_array_gin_extract_value(
+    cells: Vec<Option<S2CellId>>,
+    nkeys: Internal,
+    _null_flags: Internal,
+) -> Internal {
+    gin_keys_datum(gin_value_keys(&valid_cellids(cells)), nkeys)
+}
+
+#[pg_extern(immutable, parallel_safe)]
+fn s2cellid_array_gin_extract_query(
+    query: Vec<Option<S2CellId>>,
+    nkeys: Internal,
+    _strategy: i16,
+    _partial_match: Internal,
+    _extra_data: Internal,
+    _null_flags: Internal,
+    _search_mode: Internal,
+) -> Internal {
+    gin_keys_datum(gin_query_keys(&valid_cellids(query)), nkeys)
+}
+
+#[pg_extern(immutable, parallel_safe)]
+#[allow(clippy::too_many_arguments)]
+fn s2cellid_array_gin_consistent(
+    check: Internal,
+    _strategy: i16,
+    _query: Vec<Option<S2CellId>>,
+    nkeys: i32,
+    _extra_data: Internal,
+    recheck: Internal,
+    _query_keys: Internal,
+    _null_flags: Internal,
+) -> bool {
+    gin_any_key_matches(check, nkeys, recheck)
+}
+
+#[pg_extern(immutable, parallel_safe)]
+fn s2cellunion_gin_extract_value(
+    cells: S2CellUnion<'_>,
+    nkeys: Internal,
+    _null_flags: Internal,
+) -> Internal {
+    gin_keys_datum(gin_value_keys(&cells.cells()), nkeys)
+}
+
+#[pg_extern(immutable, parallel_safe)]
+fn s2cellunion_gin_extract_query(
+    query: S2CellUnion<'_>,
+    nkeys: Internal,
+    _strategy: i16,
+    _partial_match: Internal,
+    _extra_data: Internal,
+    _null_flags: Internal,
+    _search_mode: Internal,
+) -> Internal {
+    gin_keys_datum(gin_query_keys(&query.cells()), nkeys)
+}
+
+#[pg_extern(immutable, parallel_safe)]
+#[allow(clippy::too_many_arguments)]
+fn s2cellunion_gin_consistent(
+    check: Internal,
+    _strategy: i16,
+    _query: S2CellUnion<'_>,
+    nkeys: i32,
+    _extra_data: Internal,
+    recheck: Internal,
+    _query_keys: Internal,
+    _null_flags: Internal,
+) -> bool {
+    gin_any_key_matches(check, nkeys, recheck)
+}
+
+extension_sql!(
+    r#"
+CREATE OPERATOR && (
+    LEFTARG = s2cellid[],
+    RIGHTARG = s2cellid[],
+    FUNCTION = s2_cells_intersect,
+    COMMUTATOR = &&,
+    RESTRICT = contsel,
+    JOIN = contjoinsel
+);
+
+CREATE OPERATOR && (
+    LEFTARG = s2cellunion,
+    RIGHTARG = s2cellunion,
+    FUNCTION = s2_cellunion_intersects,
+    COMMUTATOR = &&,
+    RESTRICT = contsel,
+    JOIN = contjoinsel
+);
+
+CREATE OPERATOR CLASS s2cellid_array_gin_ops
+FOR TYPE s2cellid[] USING gin AS
+    OPERATOR 1 && (s2cellid[], s2cellid[]),
+    FUNCTION 1 btint8cmp(int8, int8),
+    FUNCTION 2 s2cellid_array_gin_extract_value(s2cellid[], internal, internal),
+    FUNCTION 3 s2cellid_array_gin_extract_query(s2cellid[], internal, int2, internal, internal, internal, internal),
+    FUNCTION 4 s2cellid_array_gin_consistent(internal, int2, s2cellid[], int4, internal, internal, internal, internal),
+    STORAGE int8;
+
+CREATE OPERATOR CLASS s2cellunion_gin_ops
+DEFAULT FOR TYPE s2cellunion USING gin AS
+    OPERATOR 1 && (s2cellunion, s2cellunion),
+    FUNCTION 1 btint8cmp(int8, int8),
+    FUNCTION 2 s2cellunion_gin_extract_value(s2cellunion, internal, internal),
+    FUNCTION 3 s2cellunion_gin_extract_query(s2cellunion, internal, int2, internal, internal, internal, internal),
+    FUNCTION 4 s2cellunion_gin_consistent(internal, int2, s2cellunion, int4, internal, internal, internal, internal),
+    STORAGE int8;
+"#,
+    name = "s2cell_gin_ops",
+    requires = [
+        "s2cellunion_casts_operators",
+        s2_cells_intersect,
+        s2_cellunion_intersects_union,
+        s2cellid_array_gin_extract_value,
+        s2cellid_array_gin_extract_query,
+        s2cellid_array_gin_consistent,
+        s2cellunion_gin_extract_value,
+        s2cellunion_gin_extract_query,
+        s2cellunion_gin_consistent
+    ],
+);
+
+#[pg_extern(immutable)]
+fn s2_cell_common_ancestor_level(a: S2CellId, b: S2CellId) -> Option<i32> {
+    let raw_a = a.to_u64();
+    let raw_b = b.to_u64();
+    if !s2_cellid_is_valid_raw(raw_a) || !s2_cellid_is_valid_raw(raw_b) {
+        error!("invalid s2cellid");
+    }
+    CellID(raw_a)
+        .common_ancestor_level(&CellID(raw_b))
+        .map(|level| level as i32)
+}
+
+#[pg_extern(immutable)]
+fn s2_cell_common_ancestor(a: S2CellId, b: S2CellId) -> Option<S2CellId> {
+    let level = s2_cell_common_ancestor_level(a, b)?;
+    let ancestor = CellID(a.to_u64()).parent(level as u64);
+    Some(S2CellId::from_u64(ancestor.0))
+}
+
+#[pg_extern(immutable)]
+fn s2_cell_ancestor_path(cell: S2CellId) -> Vec<S2CellId> {
+    let raw = cell.to_u64();
+    if !s2_cellid_is_valid_raw(raw) {
+        error!("invalid s2cellid");
+    }
+    let cellid = CellID(raw);
+    (0..=cellid.level())
+        .map(|level| S2CellId::from_u64(cellid.parent(level).0))
+        .collect()
+}
+
+/// Ancestors of `cell` (including itself) from `min_level` to `max_level`, coarsest first.
+/// Levels finer than the cell are skipped.
+#[pg_extern(immutable, parallel_safe)]
+fn s2_cell_ancestors(
+    cell: S2CellId,
+    min_level: i32,
+    max_level: i32,
+) -> SetOfIterator<'static, S2CellId> {
+    if !(0..=30).contains(&min_level) || !(0..=30).contains(&max_level) || min_level > max_level {
+        error!("invalid level");
+    }
+    cell_ancestors_at(cell, (min_level..=max_level).collect())
+}
+
+/// Ancestors of `cell` at exactly the listed levels. Passing the distinct levels of a
+/// region table turns a hierarchical join into an equality (hash) join:
+/// `JOIN regions r ON r.cell = a` over `s2_cell_ancestors(l.cell, levels) a`.
+#[pg_extern(immutable, parallel_safe, name = "s2_cell_ancestors")]
+fn s2_cell_ancestors_at_levels(
+    cell: S2CellId,
+    levels: Vec<Option<i32>>,
+) -> SetOfIterator<'static, S2CellId> {
+    let levels: Vec<i32> = levels.into_iter().flatten().collect();
+    if levels.iter().any(|level| !(0..=30).contains(level)) {
+        error!("invalid level");
+    }
+    cell_ancestors_at(cell, levels)
+}
+
+fn cell_ancestors_at(cell: S2CellId, levels: Vec<i32>) -> SetOfIterator<'static, S2CellId> {
+    let raw = cell.to_u64();
+    if !s2_cellid_is_valid_raw(raw) {
+        error!("invalid s2cellid");
+    }
+    let cellid = CellID(raw);
+    let levels: BTreeSet<u64> = levels
+        .into_iter()
+        .map(|level| level as u64)
+        .filter(|&level| level <= cellid.level())
+        .collect();
+    SetOfIterator::new(
+        levels
+            .into_iter()
+            .map(move |level| S2CellId::from_u64(cellid.parent(level).0)),
+    )
+}
+
+#[pg_extern(immutable)]
+fn s2_cell_to_children(cell: S2CellId, level: i32) -> SetOfIterator<'static, S2CellId> {
+    if !(0..=30).contains(&level) {
+        error!("invalid level");
+    }
+    let raw = cell.to_u64();
+    if !s2_cellid_is_valid_raw(raw) {
+        error!("invalid s2cellid");
+    }
+    let cellid = CellID(raw);
+    let cell_level = cellid.level() as i32;
+    if level <= cell_level {
+        error!("invalid level");
+    }
+    let mut cur = cellid.child_begin_at_level(level as u64);
+    let end = cellid.child_end_at_level(level as u64);
+    let iter = std::iter::from_fn(move || {
+        if cur == end {
+            None
+        } else {
+            let out = S2CellId::from_u64(cur.0);
+            cur = cur.next();
+            Some(out)
+        }
+    });
+    SetOfIterator::new(iter)
+}
+
+#[pg_extern(immutable, name = "s2_cell_to_children")]
+fn s2_cell_to_children_default(cell: S2CellId) -> SetOfIterator<'static, S2CellId> {
+    let raw = cell.to_u64();
+    if !s2_cellid_is_valid_raw(raw) {
+        error!("invalid s2cellid");
+    }
+    let cellid = CellID(raw);
+    let level = cellid.level();
+    if level == 30 {
+        error!("invalid level");
+    }
+    s2_cell_to_children(cell, level as i32 + 1)
+}
+
+#[pg_extern(immutable)]
+fn s2_cell_to_center_child(cell: S2CellId, level: i32) -> S2CellId {
+    if !(0..=30).contains(&level) {
+        error!("invalid level");
+    }
+    let raw = cell.to_u64();
+    if !s2_cellid_is_valid_raw(raw) {
+        error!("invalid s2cellid");
+    }
+    let cellid = CellID(raw);
+    let cell_level = cellid.level() as i32;
+    if level <= cell_level {
+        error!("invalid level");
+    }
+    let center = Cell::from(cellid).center();
+    let child = CellID::from(center).parent(level as u64);
+    S2CellId::from_u64(child.0)
+}
+
+#[pg_extern(immutable, name = "s2_cell_to_center_child")]
+fn s2_cell_to_center_child_default(cell: S2CellId) -> S2CellId {
+    let raw = cell.to_u64();
+    if !s2_cellid_is_valid_raw(raw) {
+        error!("invalid s2cellid");
+    }
+    let cellid = CellID(raw);
+    let level = cellid.level();
+    if level == 30 {
+        error!("invalid level");
+    }
+    s2_cell_to_center_child(cell, level as i32 + 1)
+}
+
+/// Size of one unit: either on the earth's surface (meters or square meters
+/// per unit) or on the unit sphere (radians or steradians per unit).
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum UnitScale {
+    Surface(f64),
+    Sphere(f64),
+}
+
+const LENGTH_UNITS: &[(&str, UnitScale)] = &[
+    ("m", UnitScale::Surface(1.0)),
+    ("km", UnitScale::Surface(1000.0)),
+    ("mi", UnitScale::Surface(1609.344)),
+    ("nmi", UnitScale::Surface(1852.0)),
+    ("ft", UnitScale::Surface(0.3048)),
+    ("rad", UnitScale::Sphere(1.0)),
+    ("deg", UnitScale::Sphere(std::f64::consts::PI / 180.0)),
+];
+
+const AREA_UNITS: &[(&str, UnitScale)] = &[
+    ("m2", UnitScale::Surface(1.0)),
+    ("km2", UnitScale::Surface(1_000_000.0)),
+    ("sr", UnitScale::Sphere(1.0)),
+];
+
+/// Looks `unit` up in `units` (case-insensitive, `²` accepted for `2`).
+fn parse_unit(unit: &str, units: &[(&str, UnitScale)]) -> UnitScale {
+    let key = unit.trim().to_ascii_lowercase().replace('²', "2");
+    match units.iter().find(|(name, _)| *name == key) {
+        Some((_, scale)) => *scale,
+        None => {
+            let supported: Vec<&str> = units.iter().map(|(name, _)| *name).collect();
+            error!("invalid unit \"{}\" (supported: {})", unit, supported.join(", "))
+        }
+    }
+}
+
+/// Distance between two points in `unit`: surface units follow
+/// `pg_s2.distance_model`, angular units are the central angle.
+fn distance_in_unit(a: &LatLng, b: &LatLng, unit: &str) -> f64 {
+    match parse_unit(unit, LENGTH_UNITS) {
+        UnitScale::Surface(meters) => distance_m(a, b) / meters,
+        UnitScale::Sphere(radians) => a.distance(b).rad() / radians,
+    }
+}
+
+#[pg_extern(stable)]
+fn s2_great_circle_distance(a: Point, b: Point, unit: &str) -> f64 {
+    let ll_a = LatLng::from_degrees(a.y, a.x);
+    let ll_b = LatLng::from_degrees(b.y, b.x);
+    if !ll_a.is_valid() || !ll_b.is_valid() {
+        error!("invalid latlng");
+    }
+    distance_in_unit(&ll_a, &ll_b, unit)
+}
+
+/// Closest point to `x` on the geodesic edge `a`-`b`.
+fn closest_point_on_edge(x: &S2Point, a: &S2Point, b: &S2Point) -> S2Point {
+    let normal = a.0.cross(&b.0);
+    if normal.norm() > 0.0 {
+        let normal = normal.normalize();
+        let projected = x.0 - normal * x.0.dot(&normal);
+        if projected.norm() > 0.0 {
+            let projected = projected.normalize();
+            if a.0.cross(&projected).dot(&normal) >= 0.0
+                && projected.cross(&b.0).dot(&normal) >= 0.0
+            {
+                return S2Point(projected);
+            }
+        }
+    }
+    if x.distance(a).rad() <= x.distance(b).rad() {
+        *a
+    } else {
+        *b
+    }
+}
+
+/// Distance from `latlng` to the nearest point of `cell`; zero inside it.
+#[pg_extern(stable)]
+fn s2_cell_distance(cell: S2CellId, latlng: Point, unit: default!(&str, "'m'")) -> f64 {
+    let raw = cell.to_u64();
+    if !s2_cellid_is_valid_raw(raw) {
+        error!("invalid s2cellid");
+    }
+    let ll = valid_latlng(latlng);
+    let x = S2Point::from(ll);
+    let cell = Cell::from(CellID(raw));
+    let closest = if cell.contains_point(&x) {
+        x
+    } else {
+        let v = cell.vertices();
+        (0..4)
+            .map(|i| closest_point_on_edge(&x, &v[i], &v[(i + 1) % 4]))
+            .min_by(|p, q| x.distance(p).rad().total_cmp(&x.distance(q).rad()))
+            .expect("cell has four vertices")
+    };
+    distance_in_unit(&ll, &LatLng::from(closest), unit)
+}
+
+/// Distance in meters under `pg_s2.distance_model`.
+fn distance_m(a: &LatLng, b: &LatLng) -> f64 {
+    match DISTANCE_MODEL.get() {
+        DistanceModel::Sphere => a.distance(b).rad() * EARTH_RADIUS_M.get(),
+        DistanceModel::Wgs84 => wgs84_distance_m(a, b)
+            .unwrap_or_else(|| error!("wgs84 distance did not converge (nearly antipodal points)")),
+    }
+}
+
+#[inline]
+fn wgs84_b() -> f64 {
+    WGS84_A * (1.0 - WGS84_F)
+}
+
+#[inline]
+fn wgs84_e2() -> f64 {
+    WGS84_F * (2.0 - WGS84_F)
+}
+
+/// Vincenty's inverse formula on the WGS84 ellipsoid. Returns `None` when the
+/// iteration does not converge, which only happens for nearly antipodal
+/// points.
+fn wgs84_distance_m(a: &LatLng, b: &LatLng) -> Option<f64> {
+    let minor = wgs84_b();
+    let l = b.lng.rad() - a.lng.rad();
+    let (sin_u1, cos_u1) = ((1.0 - WGS84_F) * a.lat.rad().tan()).atan().sin_cos();
+    let (sin_u2, cos_u2) = ((1.0 - WGS84_F) * b.lat.rad().tan()).atan().sin_cos();
+    let mut lambda = l;
+    for _ in 0..200 {
+        let (sin_lambda, cos_lambda) = lambda.sin_cos();
+        let sin_sigma = ((cos_u2 * sin_lambda).powi(2)
+            + (cos_u1 * sin_u2 - sin_u1 * cos_u2 * cos_lambda).powi(2))
+        .sqrt();
+        if sin_sigma == 0.0 {
+            return Some(0.0);
+        }
+        let cos_sigma = sin_u1 * sin_u2 + cos_u1 * cos_u2 * cos_lambda;
+        let sigma = sin_sigma.atan2(cos_sigma);
+        let sin_alpha = cos_u1 * cos_u2 * sin_lambda / sin_sigma;
+        let cos2_alpha = 1.0 - sin_alpha * sin_alpha;
+        // Equatorial lines have cos2_alpha == 0.
+        let cos_2sigma_m = if cos2_alpha == 0.0 {
+            0.0
+        } else {
+            cos_sigma - 2.0 * sin_u1 * sin_u2 / cos2_alpha
+        };
+        let c = WGS84_F / 16.0 * cos2_alpha * (4.0 + WGS84_F * (4.0 - 3.0 * cos2_alpha));
+        let previous = lambda;
+        lambda = l
+            + (1.0 - c)
+                * WGS84_F
+                * sin_alpha
+                * (sigma
+                    + c * sin_sigma
+                        * (cos_2sigma_m + c * cos_sigma * (2.0 * cos_2sigma_m.powi(2) - 1.0)));
+        if (lambda - previous).abs() < 1e-12 {
+            let u2 = cos2_alpha * (WGS84_A * WGS84_A - minor * minor) / (minor * minor);
+            let big_a =
+                1.0 + u2 / 16384.0 * (4096.0 + u2 * (-768.0 + u2 * (320.0 - 175.0 * u2)));
+            let big_b = u2 / 1024.0 * (256.0 + u2 * (-128.0 + u2 * (74.0 - 47.0 * u2)));
+            let delta_sigma = big_b
+                * sin_sigma
+                * (cos_2sigma_m
+                    + big_b / 4.0
+                        * (cos_sigma * (2.0 * cos_2sigma_m.powi(2) - 1.0)
+                            - big_b / 6.0
+                                * cos_2sigma_m
+                                * (4.0 * sin_sigma.powi(2) - 3.0)
+                                * (4.0 * cos_2sigma_m.powi(2) - 3.0)));
+            return Some(minor * big_a * (sigma - delta_sigma));
+        }
+    }
+    None
+}
+
+/// Vincenty's direct formula: the point `distance_m` along the geodesic
+/// leaving `origin` at `bearing` (radians, clockwise from north).
+fn wgs84_destination(origin: &LatLng, bearing: f64, distance_m: f64) -> LatLng {
+    let minor = wgs84_b();
+    let (sin_alpha1, cos_alpha1) = bearing.sin_cos();
+    let tan_u1 = (1.0 - WGS84_F) * origin.lat.rad().tan();
+    let cos_u1 = 1.0 / (1.0 + tan_u1 * tan_u1).sqrt();
+    let sin_u1 = tan_u1 * cos_u1;
+    let sigma1 = tan_u1.atan2(cos_alpha1);
+    let sin_alpha = cos_u1 * sin_alpha1;
+    let cos2_alpha = 1.0 - sin_alpha * sin_alpha;
+    let u2 = cos2_alpha * (WGS84_A * WGS84_A - minor * minor) / (minor * minor);
+    let big_a = 1.0 + u2 / 16384.0 * (4096.0 + u2 * (-768.0 + u2 * (320.0 - 175.0 * u2)));
+    let big_b = u2 / 1024.0 * (256.0 + u2 * (-128.0 + u2 * (74.0 - 47.0 * u2)));
+    let mut sigma = distance_m / (minor * big_a);
+    for _ in 0..200 {
+        let cos_2sigma_m = (2.0 * sigma1 + sigma).cos();
+        let (sin_sigma, cos_sigma) = sigma.sin_cos();
+        let delta_sigma = big_b
+            * sin_sigma
+            * (cos_2sigma_m
+                + big_b / 4.0
+                    * (cos_sigma * (2.0 * cos_2sigma_m.powi(2) - 1.0)
+                        - big_b / 6.0
+                            * cos_2sigma_m
+                            * (4.0 * sin_sigma.powi(2) - 3.0)
+                            * (4.0 * cos_2sigma_m.powi(2) - 3.0)));
+        let previous = sigma;
+        sigma = distance_m / (minor * big_a) + delta_sigma;
+        if (sigma - previous).abs() < 1e-12 {
+            break;
+        }
+    }
+    let cos_2sigma_m = (2.0 * sigma1 + sigma).cos();
+    let (sin_sigma, cos_sigma) = sigma.sin_cos();
+    let x = sin_u1 * sin_sigma - cos_u1 * cos_sigma * cos_alpha1;
+    let lat = (sin_u1 * cos_sigma + cos_u1 * sin_sigma * cos_alpha1)
+        .atan2((1.0 - WGS84_F) * (sin_alpha * sin_alpha + x * x).sqrt());
+    let lambda =
+        (sin_sigma * sin_alpha1).atan2(cos_u1 * cos_sigma - sin_u1 * sin_sigma * cos_alpha1);
+    let c = WGS84_F / 16.0 * cos2_alpha * (4.0 + WGS84_F * (4.0 - 3.0 * cos2_alpha));
+    let l = lambda
+        - (1.0 - c)
+            * WGS84_F
+            * sin_alpha
+            * (sigma
+                + c * sin_sigma
+                    * (cos_2sigma_m + c * cos_sigma * (2.0 * cos_2sigma_m.powi(2) - 1.0)));
+    LatLng::new(Angle::from(Rad(lat)), Angle::from(Rad(origin.lng.rad() + l)))
+}
+
+/// Radius that turns meters into an arc angle under `pg_s2.distance_model`.
+/// Under `wgs84` it is the smallest radius of curvature, `a(1 - e²)` (the
+/// meridional radius at the equator). Any path on the ellipsoid is at least
+/// that long per radian of the corresponding unit-sphere arc, so caps and
+/// margins sized with it are never too small, wherever they are centered.
+fn model_earth_radius_m() -> f64 {
+    match DISTANCE_MODEL.get() {
+        DistanceModel::Sphere => EARTH_RADIUS_M.get(),
+        DistanceModel::Wgs84 => WGS84_A * (1.0 - wgs84_e2()),
+    }
+}
+
+/// Radius of the sphere with the same surface area as the WGS84 ellipsoid.
+fn wgs84_authalic_radius_m() -> f64 {
+    WGS84_A * (authalic_q(std::f64::consts::FRAC_PI_2) / 2.0).sqrt()
+}
+
+/// `q(φ)` from Snyder's equal-area formulas, for geodetic latitude `lat` in radians.
+fn authalic_q(lat: f64) -> f64 {
+    let e2 = wgs84_e2();
+    let e = e2.sqrt();
+    let sin_lat = lat.sin();
+    (1.0 - e2)
+        * (sin_lat / (1.0 - e2 * sin_lat * sin_lat)
+            + ((1.0 + e * sin_lat) / (1.0 - e * sin_lat)).ln() / (2.0 * e))
+}
+
+/// Authalic latitude in degrees: where `lat_deg` lands on the equal-area sphere.
+fn authalic_latitude_deg(lat_deg: f64) -> f64 {
+    let ratio = authalic_q(lat_deg.to_radians()) / authalic_q(std::f64::consts::FRAC_PI_2);
+    ratio.clamp(-1.0, 1.0).asin().to_degrees()
+}
+
+#[pg_extern(stable, name = "s2_great_circle_distance")]
+fn s2_great_circle_distance_default(a: Point, b: Point) -> f64 {
+    s2_great_circle_distance(a, b, "m")
+}
+
+fn valid_latlng(p: Point) -> LatLng {
+    let ll = LatLng::from_degrees(p.y, p.x);
+    if !ll.is_valid() {
+        error!("invalid latlng");
+    }
+    ll
+}
+
+fn latlng_to_point(ll: &LatLng) -> Point {
+    Point {
+        x: ll.lng.deg(),
+        y: ll.lat.deg(),
+    }
+}
+
+/// Point reached by travelling `distance_m` leaving `origin` at `bearing_deg`
+/// (clockwise from north): along the great circle under `sphere`, along the
+/// geodesic (Vincenty's direct formula) under `wgs84`.
+#[pg_extern(stable)]
+fn s2_destination_point(origin: Point, bearing_deg: f64, distance_m: f64) -> Point {
+    let ll = valid_latlng(origin);
+    if !bearing_deg.is_finite() || !distance_m.is_finite() {
+        error!("invalid distance");
+    }
+    let bearing = bearing_deg.to_radians();
+    let (lat2, lng2) = match DISTANCE_MODEL.get() {
+        DistanceModel::Sphere => {
+            let d = distance_m / EARTH_RADIUS_M.get();
+            let (lat1, lng1) = (ll.lat.rad(), ll.lng.rad());
+            let sin_lat2 = lat1.sin() * d.cos() + lat1.cos() * d.sin() * bearing.cos();
+            let lat2 = sin_lat2.clamp(-1.0, 1.0).asin();
+            let lng2 = lng1
+                + (bearing.sin() * d.sin() * lat1.cos()).atan2(d.cos() - lat1.sin() * lat2.sin());
+            (lat2, lng2)
+        }
+        DistanceModel::Wgs84 => {
+            let dest = wgs84_destination(&ll, bearing, distance_m);
+            (dest.lat.rad(), dest.lng.rad())
+        }
+    };
+    Point {
+        x: wrap_lng_degrees(lng2.to_degrees()),
+        y: lat2.to_degrees(),
+    }
+}
+
+/// Initial great-circle bearing from `a` towards `b` in degrees, [0, 360).
+#[pg_extern(immutable)]
+fn s2_initial_bearing(a: Point, b: Point) -> f64 {
+    let (ll_a, ll_b) = (valid_latlng(a), valid_latlng(b));
+    let (lat1, lat2) = (ll_a.lat.rad(), ll_b.lat.rad());
+    let dlng = ll_b.lng.rad() - ll_a.lng.rad();
+    let y = dlng.sin() * lat2.cos();
+    let x = lat1.cos() * lat2.sin() - lat1.sin() * lat2.cos() * dlng.cos();
+    let bearing = y.atan2(x).to_degrees().rem_euclid(360.0);
+    if bearing >= 360.0 {
+        0.0
+    } else {
+        bearing
+    }
+}
+
+/// Point at `fraction` of the way along the great circle from `a` to `b`;
+/// fractions outside [0, 1] extrapolate along the same circle.
+#[pg_extern(immutable)]
+fn s2_interpolate(a: Point, b: Point, fraction: f64) -> Point {
+    let (ll_a, ll_b) = (valid_latlng(a), valid_latlng(b));
+    if !fraction.is_finite() {
+        error!("invalid fraction");
+    }
+    let p = interpolate(fraction, &S2Point::from(ll_a), &S2Point::from(ll_b));
+    latlng_to_point(&LatLng::from(p))
+}
+
+#[pg_extern(immutable)]
+fn s2_midpoint(a: Point, b: Point) -> Point {
+    s2_interpolate(a, b, 0.5)
+}
+
+/// `2 (a × b)`, computed as `(a + b) × (b - a)` so the direction stays
+/// accurate when `a` and `b` are millimeters apart.
+fn robust_cross(a: &S2Point, b: &S2Point) -> Vector {
+    (a.0 + b.0).cross(&(b.0 - a.0))
+}
+
+/// Loop with geodesic edges, oriented counterclockwise around the smaller of
+/// the two regions it bounds, the way S2 normalizes loops.
+struct SphericalLoop {
+    vertices: Vec<S2Point>,
+    area: f64,
+}
+
+impl SphericalLoop {
+    fn from_points(points: &[Point]) -> Option<Self> {
+        let mut vertices: Vec<S2Point> = Vec::with_capacity(points.len());
+        for p in points {
+            let ll = LatLng::from_degrees(p.y, p.x);
+            if !ll.is_valid() {
+                return None;
+            }
+            let v = S2Point::from(ll);
+            if vertices.last() != Some(&v) {
+                vertices.push(v);
+            }
+        }
+        if vertices.len() > 1 && vertices.first() == vertices.last() {
+            vertices.pop();
+        }
+        if vertices.len() < 3 {
+            return None;
+        }
+        let signed: f64 = (1..vertices.len() - 1)
+            .map(|i| {
+                let (a, b, c) = (&vertices[0], &vertices[i], &vertices[i + 1]);
+                let area = point_area(a, b, c);
+                if a.0.dot(&(b.0 - a.0).cross(&(c.0 - a.0))) < 0.0 {
+                    -area
+                } else {
+                    area
+                }
+            })
+            .sum();
+        if signed < 0.0 {
+            vertices.reverse();
+        }
+        let mut area = signed.abs();
+        if area > 2.0 * std::f64::consts::PI {
+            vertices.reverse();
+            area = 4.0 * std::f64::consts::PI - area;
+        }
+        Some(SphericalLoop { vertices, area })
+    }
+
+    /// Area-weighted true centroid (not normalized).
+    fn centroid(&self) -> Vector {
+        let v = &self.vertices;
+        (1..v.len() - 1)
+            .map(|i| true_centroid(&v[0], &v[i], &v[i + 1]).0)
+            .fold(Vector::new(0.0, 0.0, 0.0), |acc, c| acc + c)
+    }
+
+    /// Crossing parity against a reference point inside the loop.
+    fn contains(&self, p: &S2Point) -> bool {
+        !self.crosses_odd(&self.reference_point(), p)
+    }
+
+    /// Point just left of (inside) the midpoint of the first edge. The offset
+    /// is half the distance to the nearest other edge, so no edge can pass
+    /// between the two however small or narrow the loop is.
+    fn reference_point(&self) -> S2Point {
+        let v = &self.vertices;
+        let mid = S2Point((v[0].0 + v[1].0).normalize());
+        let clearance = (1..v.len())
+            .map(|i| distance_from_segment(&mid, &v[i], &v[(i + 1) % v.len()]).rad())
+            .fold(f64::INFINITY, f64::min);
+        let inward = robust_cross(&v[0], &v[1]).normalize();
+        S2Point((mid.0 + inward * (clearance / 2.0)).normalize())
+    }
+
+    /// True when the arc `a`-`b` crosses the loop an odd number of times.
+    /// Each vertex is classified once against the arc's great circle, with a
+    /// vertex exactly on it counted on the negative side (a half-open rule).
+    /// An arc through a vertex therefore counts one crossing when the loop
+    /// passes through it and zero or two when the loop only touches.
+    fn crosses_odd(&self, a: &S2Point, b: &S2Point) -> bool {
+        let v = &self.vertices;
+        let normal = robust_cross(a, b);
+        let above: Vec<bool> = v.iter().map(|x| normal.dot(&x.0) > 0.0).collect();
+        let crossings = (0..v.len())
+            .filter(|&i| {
+                let j = (i + 1) % v.len();
+                if above[i] == above[j] {
+                    return false;
+                }
+                // The edge straddles the great circle; it crosses the arc when
+                // `a` and `b` also lie on opposite sides of the edge, turning
+                // the same way.
+                let edge_normal = robust_cross(&v[i], &v[j]);
+                (edge_normal.dot(&a.0) > 0.0) == above[j]
+                    && (edge_normal.dot(&b.0) < 0.0) == above[j]
+            })
+            .count();
+        crossings % 2 == 1
+    }
+}
+
+fn parse_spherical_loop(polygon: &str) -> SphericalLoop {
+    parse_pg_points(polygon)
+        .and_then(|(points, _)| SphericalLoop::from_points(&points))
+        .unwrap_or_else(|| error!("invalid polygon"))
+}
+
+/// Area in `unit`. Under `wgs84`, surface units map the vertices to authalic
+/// latitudes and measure on the equal-area sphere, which preserves ellipsoidal
+/// areas; `sr` is always the solid angle of the loop as given.
+#[pg_extern(stable)]
+fn s2_polygon_area_text(polygon: &str, unit: &str) -> f64 {
+    match parse_unit(unit, AREA_UNITS) {
+        UnitScale::Surface(square_meters) => {
+            let (area, radius) = match DISTANCE_MODEL.get() {
+                DistanceModel::Sphere => (parse_spherical_loop(polygon).area, EARTH_RADIUS_M.get()),
+                DistanceModel::Wgs84 => {
+                    let area = parse_pg_points(polygon)
+                        .and_then(|(mut points, _)| {
+                            for p in &mut points {
+                                if p.y.abs() <= 90.0 {
+                                    p.y = authalic_latitude_deg(p.y);
+                                }
+                            }
+                            SphericalLoop::from_points(&points)
+                        })
+                        .unwrap_or_else(|| error!("invalid polygon"))
+                        .area;
+                    (area, wgs84_authalic_radius_m())
+                }
+            };
+            area * radius * radius / square_meters
+        }
+        UnitScale::Sphere(steradians) => parse_spherical_loop(polygon).area / steradians,
+    }
+}
+
+#[pg_extern(immutable)]
+fn s2_polygon_centroid_text(polygon: &str) -> Point {
+    let centroid = parse_spherical_loop(polygon).centroid();
+    if centroid.norm() == 0.0 {
+        error!("invalid polygon");
+    }
+    let ll = LatLng::from(S2Point(centroid.normalize()));
+    Point {
+        x: ll.lng.deg(),
+        y: ll.lat.deg(),
+    }
+}
+
+#[pg_extern(immutable)]
+fn s2_polygon_contains_text(polygon: &str, latlng: Point) -> bool {
+    let ll = LatLng::from_degrees(latlng.y, latlng.x);
+    if !ll.is_valid() {
+        error!("invalid latlng");
+    }
+    parse_spherical_loop(polygon).contains(&S2Point::from(ll))
+}
+
+extension_sql!(
+    r#"
+CREATE FUNCTION s2_polygon_area(polygon polygon, unit text DEFAULT 'm2')
+RETURNS double precision
+STABLE PARALLEL SAFE
+LANGUAGE SQL
+AS $$ SELECT s2_polygon_area_text($1::text, $2) $$;
+
+CREATE FUNCTION s2_polygon_centroid(polygon polygon)
+RETURNS point
+IMMUTABLE PARALLEL SAFE
+LANGUAGE SQL
+AS $$ SELECT s2_polygon_centroid_text($1::text) $$;
+
+CREATE FUNCTION s2_polygon_contains(polygon polygon, latlng point)
+RETURNS boolean
+IMMUTABLE PARALLEL SAFE
+LANGUAGE SQL
+AS $$ SELECT s2_polygon_contains_text($1::text, $2) $$;
+"#,
+    name = "s2_polygon_measures",
+    requires = [s2_polygon_area_text, s2_polygon_centroid_text, s2_polygon_contains_text],
+);
+
+#[cfg(any(test, feature = "pg_test"))]
+#[pg_schema]
+mod tests {
+    use super::*;
+    use s2::cell::Cell;
+    use s2::cellid::CellID;
+    use s2::latlng::LatLng;
+    use pgrx::spi::Spi;
+
+    #[pg_test]
+    fn test_s2_get_extension_version_matches_pkg() {
+        let v = s2_get_extension_version();
+        assert_eq!(v, env!("CARGO_PKG_VERSION"));
+    }
+
+    #[pg_test]
+    fn test_i64_norm_roundtrip() {
+        let cases = [
+            0u64,
             1u64,
             0x7fff_ffff_ffff_ffff,
             0x8000_0000_0000_0000,
             u64::MAX,
         ];
 
-        for &v in &cases {
-            let norm = u64_to_i64_norm(v);
-            let back = i64_norm_to_u64(norm);
-            assert_eq!(back, v);
+        for &v in &cases {
+            let norm = u64_to_i64_norm(v);
+            let back = i64_norm_to_u64(norm);
+            assert_eq!(back, v);
+        }
+    }
+
+    #[pg_test]
+    fn test_i64_norm_order_preserving_unsigned() {
+        let pairs = [
+            (0u64, 1u64),
+            (1u64, 2u64),
+            (0x7fff_ffff_ffff_fffe, 0x7fff_ffff_ffff_ffff),
+            (0x7fff_ffff_ffff_ffff, 0x8000_0000_0000_0000),
+            (0x8000_0000_0000_0000, u64::MAX),
+        ];
+
+        for &(a, b) in &pairs {
+            let na = u64_to_i64_norm(a);
+            let nb = u64_to_i64_norm(b);
+            assert!(na < nb, "order violated for {a:#x} < {b:#x}");
+        }
+    }
+
+    #[pg_test]
+    fn test_s2cellid_sql_ordering() {
+        let a = "47a1cbd595522b39";
+        let b = "b09dff882a7809e1";
+        let expected = CellID::from_token(a).0 < CellID::from_token(b).0;
+        let got = Spi::get_one::<bool>(&format!(
+            "SELECT ('{a}'::text::s2cellid) < ('{b}'::text::s2cellid)"
+        ))
+        .expect("spi");
+        assert_eq!(got, Some(expected));
+    }
+
+    #[pg_test]
+    fn test_s2_cell_token_roundtrip() {
+        let token = "47a1cbd595522b39";
+        let cell = s2_cell_from_token(token);
+        let back = s2_cell_to_token(cell);
+        assert_eq!(back, token);
+    }
+
+    #[pg_test]
+    fn test_s2_cell_token_roundtrip_high_bit() {
+        let token = "b112966aaaaaaaab";
+        let cell = s2_cell_from_token(token);
+        let back = s2_cell_to_token(cell);
+        assert_eq!(back, token);
+    }
+
+    #[pg_test]
+    #[should_panic(expected = "invalid s2cellid token")]
+    fn test_s2_cell_from_token_invalid() {
+        let _ = s2_cell_from_token("zz");
+    }
+
+    #[pg_test]
+    fn test_s2_cell_string_roundtrip() {
+        let token = "47a1cbd4";
+        let cell = s2_cell_from_token(token);
+        let s = s2_cell_to_string(cell);
+        assert_eq!(s, "2/0331003211322");
+        assert_eq!(s2_cell_to_token(s2_cell_from_string(&s)), token);
+
+        let face = s2_cell_from_token("9");
+        assert_eq!(s2_cell_to_string(face), "4/");
+        assert_eq!(s2_cell_to_token(s2_cell_from_string("4/")), "9");
+    }
+
+    #[pg_test]
+    #[should_panic(expected = "invalid s2cellid string")]
+    fn test_s2_cell_from_string_invalid() {
+        let _ = s2_cell_from_string("4/0241");
+    }
+
+    #[pg_test]
+    fn test_s2_cell_from_string_is_strict() {
+        assert!(cellid_from_path_string("3/012").is_some());
+        for input in ["+3/012", " 3/012", "3 /012", "03/012", "6/0", "3/012 ", "/012"] {
+            assert!(cellid_from_path_string(input).is_none(), "{input:?}");
+        }
+    }
+
+    #[pg_test]
+    fn test_s2cellid_input_accepts_string() {
+        let got = Spi::get_one::<String>("SELECT ('2/0331003211322'::s2cellid)::text")
+            .expect("spi");
+        assert_eq!(got, Some("47a1cbd4".to_string()));
+    }
+
+    #[pg_test]
+    fn test_s2cellid_text_cast_accepts_string() {
+        let got = Spi::get_one::<bool>(
+            "SELECT '2/0331003211322'::text::s2cellid = '2/0331003211322'::s2cellid \
+                AND '47a1cbd4'::text::s2cellid = '47a1cbd4'::s2cellid",
+        )
+        .expect("spi");
+        assert_eq!(got, Some(true));
+    }
+
+    #[pg_test]
+    fn test_s2_cell_to_bigint() {
+        let token = "47a1cbd595522b39";
+        let cell = s2_cell_from_token(token);
+        let expected = u64_to_i64_norm(CellID::from_token(token).0);
+        assert_eq!(s2_cell_to_bigint(cell), expected);
+    }
+
+    #[pg_test]
+    fn test_s2_cell_from_bigint_roundtrip() {
+        let token = "47a1cbd595522b39";
+        let cell = s2_cell_from_token(token);
+        let id = s2_cell_to_bigint(cell);
+        let back = s2_cell_from_bigint(id);
+        assert_eq!(s2_cell_to_token(back), token);
+    }
+
+    #[pg_test]
+    fn test_s2_cell_int64_raw_roundtrip() {
+        let token = "b112966aaaaaaaab";
+        let expected = CellID::from_token(token).0 as i64;
+        assert!(expected < 0);
+        let cell = s2_cell_from_token(token);
+        assert_eq!(s2_cell_to_int64_raw(cell), expected);
+        assert_eq!(s2_cell_to_token(s2_cell_from_int64_raw(expected)), token);
+    }
+
+    #[pg_test]
+    fn test_s2_cell_numeric_casts() {
+        let token = "b112966aaaaaaaab";
+        let expected = CellID::from_token(token).0.to_string();
+        let got = Spi::get_one::<String>(&format!(
+            "SELECT (s2_cell_from_token('{token}')::numeric)::text"
+        ))
+        .expect("spi");
+        assert_eq!(got, Some(expected.clone()));
+
+        let back = Spi::get_one::<String>(&format!("SELECT ({expected}::numeric::s2cellid)::text"))
+            .expect("spi");
+        assert_eq!(back, Some(token.to_string()));
+    }
+
+    #[pg_test]
+    #[should_panic(expected = "invalid s2cellid numeric")]
+    fn test_s2_cell_from_numeric_negative() {
+        let _ = Spi::get_one::<String>("SELECT s2_cell_from_numeric(-1)::text");
+    }
+
+    #[pg_test]
+    fn test_s2_is_valid_cell() {
+        let valid = s2_cell_from_token("47a1cbd595522b39");
+        assert!(s2_is_valid_cell(valid));
+
+        let valid_high_bit = s2_cell_from_token("b112966aaaaaaaab");
+        assert!(s2_is_valid_cell(valid_high_bit));
+
+        let invalid = s2_cell_from_bigint(0);
+        assert!(!s2_is_valid_cell(invalid));
+    }
+
+    #[pg_test]
+    fn test_s2_get_level_and_face() {
+        let face0 = s2_cell_from_token("1");
+        assert_eq!(s2_get_level(face0), 0);
+        assert_eq!(s2_get_face(face0), 0);
+
+        let face1 = s2_cell_from_token("3");
+        assert_eq!(s2_get_level(face1), 0);
+        assert_eq!(s2_get_face(face1), 1);
+    }
+
+    #[pg_test]
+    fn test_s2_cell_face_ij_roundtrip() {
+        let token = "47a1cbd595522b39";
+        let cell_raw = CellID::from_token(token);
+        let expected = cell_raw.face_ij_orientation();
+        let cell = s2_cell_from_token(token);
+        let (face, i, j, orientation) = s2_cell_to_face_ij(cell).next().expect("row");
+        assert_eq!(face, expected.0 as i32);
+        assert_eq!(i, expected.1);
+        assert_eq!(j, expected.2);
+        assert_eq!(orientation, expected.3 as i32);
+
+        let level = cell_raw.level() as i32;
+        let back = s2_cell_from_face_ij(face, i, j, level);
+        assert_eq!(s2_cell_to_token(back), token);
+    }
+
+    #[pg_test]
+    #[should_panic(expected = "invalid face")]
+    fn test_s2_cell_from_face_ij_invalid_face() {
+        let _ = s2_cell_from_face_ij(6, 0, 0, 10);
+    }
+
+    #[pg_test]
+    fn test_s2_cell_to_uv_and_st_bounds() {
+        let token = "47a1cbd595522b39";
+        let cell_raw = CellID::from_token(token);
+        let cell = s2_cell_from_token(token);
+
+        let uv = s2_cell_to_uv_bounds(cell);
+        let expected_uv = cell_raw.bound_uv();
+        assert!((uv.low.x - expected_uv.x.lo).abs() < 1e-15);
+        assert!((uv.low.y - expected_uv.y.lo).abs() < 1e-15);
+        assert!((uv.high.x - expected_uv.x.hi).abs() < 1e-15);
+        assert!((uv.high.y - expected_uv.y.hi).abs() < 1e-15);
+
+        let st = s2_cell_to_st_bounds(cell);
+        let expected_st = cell_raw.bound_st();
+        assert!((st.low.x - expected_st.x.lo).abs() < 1e-15);
+        assert!((st.low.y - expected_st.y.lo).abs() < 1e-15);
+        assert!((st.high.x - expected_st.x.hi).abs() < 1e-15);
+        assert!((st.high.y - expected_st.y.hi).abs() < 1e-15);
+    }
+
+    #[pg_test]
+    fn test_s2_lat_lng_to_cell_level() {
+        let lat = 49.703498679;
+        let lng = 11.770681595;
+        let level = 12i32;
+        let ll = LatLng::from_degrees(lat, lng);
+        let expected = CellID::from(ll).parent(level as u64).to_token();
+
+        let got = s2_lat_lng_to_cell(Point { x: lng, y: lat }, level);
+        assert_eq!(s2_cell_to_token(got), expected);
+    }
+
+    #[pg_test]
+    #[should_panic(expected = "invalid level")]
+    fn test_s2_lat_lng_to_cell_level_invalid() {
+        let _ = s2_lat_lng_to_cell(Point { x: 0.0, y: 0.0 }, 31);
+    }
+
+    #[pg_test]
+    fn test_s2_lat_lng_to_cell_default_level() {
+        let lat = 49.703498679;
+        let lng = 11.770681595;
+        let expected = s2_lat_lng_to_cell(Point { x: lng, y: lat }, 14);
+
+        let got = s2_lat_lng_to_cell_default(Point { x: lng, y: lat });
+        assert_eq!(s2_cell_to_token(got), s2_cell_to_token(expected));
+    }
+
+    #[pg_test]
+    fn test_s2_lat_lng_to_cell_default_level_guc() {
+        let lat = 49.703498679;
+        let lng = 11.770681595;
+        Spi::run("SET pg_s2.default_level = 10").expect("set GUC");
+
+        let expected = s2_lat_lng_to_cell(Point { x: lng, y: lat }, 10);
+        let got = s2_lat_lng_to_cell_default(Point { x: lng, y: lat });
+        assert_eq!(s2_cell_to_token(got), s2_cell_to_token(expected));
+    }
+
+    #[pg_test]
+    fn test_s2_lat_lng_to_cells() {
+        let points = vec![
+            Some(Point {
+                x: 11.770681595,
+                y: 49.703498679,
+            }),
+            None,
+            Some(Point {
+                x: 139.767,
+                y: 35.681,
+            }),
+        ];
+        let got = s2_lat_lng_to_cells(points.clone(), 12);
+        assert_eq!(got.len(), 3);
+        assert_eq!(got[0], Some(s2_lat_lng_to_cell(points[0].unwrap(), 12)));
+        assert_eq!(got[1], None);
+        assert_eq!(got[2], Some(s2_lat_lng_to_cell(points[2].unwrap(), 12)));
+    }
+
+    #[pg_test]
+    fn test_s2_cells_tokens_roundtrip_sql() {
+        let got = Spi::get_one::<String>(
+            "SELECT array_to_string(\
+                 s2_cells_to_tokens(s2_cells_from_tokens(ARRAY['47a1cbd595522b39', NULL, '1'])), \
+                 ',', 'NULL')",
+        )
+        .expect("spi");
+        assert_eq!(got, Some("47a1cbd595522b39,NULL,1".to_string()));
+    }
+
+    #[pg_test]
+    fn test_s2_cells_to_parent() {
+        let token = "47a1cbd595522b39";
+        let cell = s2_cell_from_token(token);
+        let expected = s2_cell_to_parent(cell, 10);
+        let got = s2_cells_to_parent(vec![Some(cell), None], 10);
+        assert_eq!(got, vec![Some(expected), None]);
+    }
+
+    #[pg_test]
+    fn test_s2_cell_to_lat_lng() {
+        let cell = s2_cell_from_token("47a1cbd595522b39");
+        let ll = s2_cell_to_lat_lng(cell);
+        assert!((ll.y - 49.703498679).abs() < 1e-6);
+        assert!((ll.x - 11.770681595).abs() < 1e-6);
+    }
+
+    #[pg_test]
+    fn test_s2_cell_bbox() {
+        let token = "47a1cbd595522b39";
+        let cell = s2_cell_from_token(token);
+        let bbox = s2_cell_bbox(cell);
+        let rect = Cell::from(CellID::from_token(token)).rect_bound();
+        assert!((bbox.low.x - rect.lng_lo().deg()).abs() < 1e-12);
+        assert!((bbox.low.y - rect.lat_lo().deg()).abs() < 1e-12);
+        assert!((bbox.high.x - rect.lng_hi().deg()).abs() < 1e-12);
+        assert!((bbox.high.y - rect.lat_hi().deg()).abs() < 1e-12);
+    }
+
+    #[pg_test]
+    #[should_panic(expected = "invalid s2cellid")]
+    fn test_s2_cell_to_lat_lng_invalid() {
+        let _ = s2_cell_to_lat_lng(s2_cell_from_bigint(0));
+    }
+
+    #[pg_test]
+    fn test_s2_cell_range_min() {
+        let token = "47a1cbd595522b39";
+        let cell = s2_cell_from_token(token);
+        let expected = CellID::from_token(token).range_min().to_token();
+        let got = s2_cell_range_min(cell);
+        assert_eq!(s2_cell_to_token(got), expected);
+    }
+
+    #[pg_test]
+    fn test_s2_cell_range_max() {
+        let token = "47a1cbd595522b39";
+        let cell = s2_cell_from_token(token);
+        let expected = CellID::from_token(token).range_max().to_token();
+        let got = s2_cell_range_max(cell);
+        assert_eq!(s2_cell_to_token(got), expected);
+    }
+
+    #[pg_test]
+    fn test_s2_cell_to_parent_level() {
+        let token = "47a1cbd595522b39";
+        let cell_raw = CellID::from_token(token);
+        assert!(cell_raw.level() > 0);
+        let level = cell_raw.level() as i32 - 1;
+        let expected = cell_raw.parent(level as u64).to_token();
+        let cell = s2_cell_from_token(token);
+        let got = s2_cell_to_parent(cell, level);
+        assert_eq!(s2_cell_to_token(got), expected);
+    }
+
+    #[pg_test]
+    fn test_s2_cell_to_parent_default() {
+        let token = "47a1cbd595522b39";
+        let cell_raw = CellID::from_token(token);
+        assert!(cell_raw.level() > 0);
+        let expected = cell_raw.parent(cell_raw.level() - 1).to_token();
+        let cell = s2_cell_from_token(token);
+        let got = s2_cell_to_parent_default(cell);
+        assert_eq!(s2_cell_to_token(got), expected);
+    }
+
+    #[pg_test]
+    fn test_s2_cell_common_ancestor() {
+        let ll_a = LatLng::from_degrees(49.703498679, 11.770681595);
+        let ll_b = LatLng::from_degrees(49.713498679, 11.780681595);
+        let raw_a = CellID::from(ll_a).parent(20);
+        let raw_b = CellID::from(ll_b).parent(16);
+        let expected_level = raw_a.common_ancestor_level(&raw_b).expect("same face");
+        let a = s2_cell_from_token(&raw_a.to_token());
+        let b = s2_cell_from_token(&raw_b.to_token());
+
+        assert_eq!(
+            s2_cell_common_ancestor_level(a, b),
+            Some(expected_level as i32)
+        );
+        let ancestor = s2_cell_common_ancestor(a, b).expect("ancestor");
+        assert_eq!(
+            s2_cell_to_token(ancestor),
+            raw_a.parent(expected_level).to_token()
+        );
+        assert!(raw_a.parent(expected_level).contains(&raw_b));
+    }
+
+    #[pg_test]
+    fn test_s2_cell_common_ancestor_different_faces() {
+        let a = s2_cell_from_token("1");
+        let b = s2_cell_from_token("3");
+        assert_eq!(s2_cell_common_ancestor_level(a, b), None);
+        assert!(s2_cell_common_ancestor(a, b).is_none());
+    }
+
+    #[pg_test]
+    fn test_s2_cell_ancestor_path() {
+        let token = "47a1cbd595522b39";
+        let cell_raw = CellID::from_token(token);
+        let expected: Vec<String> = (0..=cell_raw.level())
+            .map(|level| cell_raw.parent(level).to_token())
+            .collect();
+        let cell = s2_cell_from_token(token);
+        let got: Vec<String> = s2_cell_ancestor_path(cell)
+            .into_iter()
+            .map(s2_cell_to_token)
+            .collect();
+        assert_eq!(got, expected);
+        assert_eq!(got.last().map(String::as_str), Some(token));
+    }
+
+    #[pg_test]
+    fn test_s2_cell_to_children_level() {
+        let ll = LatLng::from_degrees(49.703498679, 11.770681595);
+        let cell_raw = CellID::from(ll).parent(10);
+        let level = cell_raw.level() as i32 + 1;
+        let expected: Vec<String> = cell_raw.children().iter().map(|c| c.to_token()).collect();
+        let cell = s2_cell_from_token(&cell_raw.to_token());
+        let got: Vec<String> = s2_cell_to_children(cell, level)
+            .map(s2_cell_to_token)
+            .collect();
+        assert_eq!(got, expected);
+    }
+
+    #[pg_test]
+    fn test_s2_cell_to_children_default() {
+        let ll = LatLng::from_degrees(49.703498679, 11.770681595);
+        let cell_raw = CellID::from(ll).parent(10);
+        let expected: Vec<String> = cell_raw.children().iter().map(|c| c.to_token()).collect();
+        let cell = s2_cell_from_token(&cell_raw.to_token());
+        let got: Vec<String> = s2_cell_to_children_default(cell)
+            .map(s2_cell_to_token)
+            .collect();
+        assert_eq!(got, expected);
+    }
+
+    #[pg_test]
+    fn test_s2_cell_to_center_child_level() {
+        let ll = LatLng::from_degrees(49.703498679, 11.770681595);
+        let cell_raw = CellID::from(ll).parent(10);
+        let level = cell_raw.level() as i32 + 1;
+        let expected = CellID::from(Cell::from(cell_raw).center())
+            .parent(level as u64)
+            .to_token();
+        let cell = s2_cell_from_token(&cell_raw.to_token());
+        let got = s2_cell_to_center_child(cell, level);
+        assert_eq!(s2_cell_to_token(got), expected);
+    }
+
+    #[pg_test]
+    fn test_s2_cell_to_center_child_default() {
+        let ll = LatLng::from_degrees(49.703498679, 11.770681595);
+        let cell_raw = CellID::from(ll).parent(10);
+        let level = cell_raw.level() as i32 + 1;
+        let expected = CellID::from(Cell::from(cell_raw).center())
+            .parent(level as u64)
+            .to_token();
+        let cell = s2_cell_from_token(&cell_raw.to_token());
+        let got = s2_cell_to_center_child_default(cell);
+        assert_eq!(s2_cell_to_token(got), expected);
+    }
+
+    #[pg_test]
+    fn test_s2_cell_to_vertices() {
+        let token = "47a1cbd595522b39";
+        let cell_raw = CellID::from_token(token);
+        let expected: Vec<Point> = Cell::from(cell_raw)
+            .vertices()
+            .iter()
+            .map(|v| {
+                let ll = LatLng::from(*v);
+                Point {
+                    x: ll.lng.deg(),
+                    y: ll.lat.deg(),
+                }
+            })
+            .collect();
+        let cell = s2_cell_from_token(token);
+        let got = s2_cell_to_vertices(cell);
+        assert_eq!(got.len(), expected.len());
+        for (a, b) in got.iter().zip(expected.iter()) {
+            assert!((a.x - b.x).abs() < 1e-6);
+            assert!((a.y - b.y).abs() < 1e-6);
+        }
+    }
+
+    #[pg_test]
+    fn test_s2_cell_boundary_text() {
+        let token = "47a1cbd595522b39";
+        let cell_raw = CellID::from_token(token);
+        let points: Vec<Point> = Cell::from(cell_raw)
+            .vertices()
+            .iter()
+            .map(|v| {
+                let ll = LatLng::from(*v);
+                Point {
+                    x: ll.lng.deg(),
+                    y: ll.lat.deg(),
+                }
+            })
+            .collect();
+        let expected = format_polygon_points(&points);
+        let cell = s2_cell_from_token(token);
+        let got = s2_cell_boundary_text(cell);
+        assert_eq!(got, expected);
+    }
+
+    #[pg_test]
+    fn test_s2_cell_boundary_text_segments() {
+        let token = "47a1cbd595522b39";
+        let cell = s2_cell_from_token(token);
+        assert_eq!(
+            s2_cell_boundary_text_segments(cell, 1, "none"),
+            s2_cell_boundary_text(cell)
+        );
+
+        let face = s2_cell_from_token("9");
+        let dense = cell_boundary_points(CellID::from_token("9"), 8);
+        assert_eq!(dense.len(), 32);
+        let text = s2_cell_boundary_text_segments(face, 8, "none");
+        assert_eq!(text, format_polygon_points(&dense));
+    }
+
+    #[pg_test]
+    fn test_s2_cell_boundary_extend_antimeridian() {
+        // Face 3 is centered on lng 180.
+        let cellid = CellID::from_face(3);
+        let points = extend_antimeridian(&cell_boundary_points(cellid, 4), cell_pole_lat(cellid));
+        assert!(points.iter().all(|p| p.x >= -180.0));
+        assert!(points.iter().any(|p| p.x > 180.0));
+        for pair in points.windows(2) {
+            assert!((pair[1].x - pair[0].x).abs() <= 180.0);
         }
     }
 
     #[pg_test]
-    fn test_i64_norm_order_preserving_unsigned() {
-        let pairs = [
-            (0u64, 1u64),
-            (1u64, 2u64),
-            (0x7fff_ffff_ffff_fffe, 0x7fff_ffff_ffff_ffff),
-            (0x7fff_ffff_ffff_ffff, 0x8000_0000_0000_0000),
-            (0x8000_0000_0000_0000, u64::MAX),
-        ];
+    #[should_panic(expected = "invalid segments")]
+    fn test_s2_cell_boundary_text_too_many_segments() {
+        let _ = s2_cell_boundary_text_segments(s2_cell_from_token("7"), i32::MAX, "none");
+    }
 
-        for &(a, b) in &pairs {
-            let na = u64_to_i64_norm(a);
-            let nb = u64_to_i64_norm(b);
-            assert!(na < nb, "order violated for {a:#x} < {b:#x}");
+    #[pg_test]
+    #[should_panic(expected = "invalid antimeridian mode")]
+    fn test_s2_cell_boundary_text_invalid_mode() {
+        let _ = s2_cell_boundary_text_segments(s2_cell_from_token("7"), 1, "wrap");
+    }
+
+    #[pg_test]
+    fn test_s2_cell_to_boundary_split_sql() {
+        let crossing = Spi::get_one::<i64>(
+            "SELECT count(*) FROM s2_cell_to_boundary_split(s2_cell_from_token('7'), 4)",
+        )
+        .expect("spi");
+        assert_eq!(crossing, Some(2));
+
+        let local = Spi::get_one::<i64>(
+            "SELECT count(*) FROM s2_cell_to_boundary_split(s2_cell_from_token('47a1cbd4'))",
+        )
+        .expect("spi");
+        assert_eq!(local, Some(1));
+
+        let within = Spi::get_one::<bool>(
+            "SELECT bool_and(box(p) <@ box(point(-180, -90), point(180, 90))) \
+             FROM s2_cell_to_boundary_split(s2_cell_from_token('7'), 4) AS p",
+        )
+        .expect("spi");
+        assert_eq!(within, Some(true));
+    }
+
+    #[pg_test]
+    fn test_s2_cell_to_geojson_polygon() {
+        let geom = s2_cell_to_geojson(s2_cell_from_token("47a1cbd4")).0;
+        assert_eq!(geom["type"], "Polygon");
+        let ring = geom["coordinates"][0].as_array().expect("ring");
+        assert_eq!(ring.len(), 5);
+        assert_eq!(ring[0], ring[4]);
+        let twice_area: f64 = ring
+            .windows(2)
+            .map(|w| {
+                let (p, q) = (&w[0], &w[1]);
+                p[0].as_f64().unwrap() * q[1].as_f64().unwrap()
+                    - q[0].as_f64().unwrap() * p[1].as_f64().unwrap()
+            })
+            .sum();
+        assert!(twice_area > 0.0, "exterior ring must be counterclockwise");
+    }
+
+    #[pg_test]
+    fn test_s2_cell_to_geojson_antimeridian() {
+        let geom = s2_cell_to_geojson(s2_cell_from_token("7")).0;
+        assert_eq!(geom["type"], "MultiPolygon");
+        let polygons = geom["coordinates"].as_array().expect("polygons");
+        assert_eq!(polygons.len(), 2);
+        for polygon in polygons {
+            for coord in polygon[0].as_array().expect("ring") {
+                let lng = coord[0].as_f64().unwrap();
+                assert!((-180.0..=180.0).contains(&lng));
+            }
         }
     }
 
     #[pg_test]
-    fn test_s2cellid_sql_ordering() {
-        let a = "47a1cbd595522b39";
-        let b = "b09dff882a7809e1";
-        let expected = CellID::from_token(a).0 < CellID::from_token(b).0;
-        let got = Spi::get_one::<bool>(&format!(
-            "SELECT ('{a}'::text::s2cellid) < ('{b}'::text::s2cellid)"
+    fn test_s2_cells_to_geojson_feature_collection_sql() {
+        let got = Spi::get_one::<pgrx::JsonB>(
+            "SELECT s2_cells_to_geojson_feature_collection(\
+                ARRAY['47a1cbd4'::s2cellid, NULL, '7'::s2cellid], \
+                ARRAY['{\"n\": 1}'::jsonb, '{}'::jsonb, NULL])::jsonb",
+        )
+        .expect("spi")
+        .expect("json")
+        .0;
+        assert_eq!(got["type"], "FeatureCollection");
+        let features = got["features"].as_array().expect("features");
+        assert_eq!(features.len(), 2);
+        assert_eq!(features[0]["id"], "47a1cbd4");
+        assert_eq!(features[0]["properties"]["n"], 1);
+        assert_eq!(features[1]["properties"], Value::Null);
+    }
+
+    #[pg_test]
+    #[should_panic(expected = "properties length must match cells length")]
+    fn test_s2_cells_to_geojson_feature_collection_length_mismatch() {
+        Spi::run(
+            "SELECT s2_cells_to_geojson_feature_collection(\
+                ARRAY['47a1cbd4'::s2cellid], ARRAY['{}'::jsonb, '{}'::jsonb])",
+        )
+        .expect("spi");
+    }
+
+    #[pg_test]
+    fn test_s2_geojson_agg_sql() {
+        let got = Spi::get_one::<pgrx::JsonB>(
+            "SELECT s2_geojson_agg(cell, jsonb_build_object('token', s2_cell_to_token(cell)))::jsonb \
+             FROM s2_cell_to_children(s2_cell_from_token('47a1cbd4')) AS cell",
+        )
+        .expect("spi")
+        .expect("json")
+        .0;
+        let features = got["features"].as_array().expect("features");
+        assert_eq!(features.len(), 4);
+        for feature in features {
+            assert_eq!(feature["id"], feature["properties"]["token"]);
+        }
+
+        let empty = Spi::get_one::<pgrx::JsonB>(
+            "SELECT s2_geojson_agg(cell, NULL)::jsonb FROM s2_cell_to_children('47a1cbd4'::s2cellid) AS cell WHERE false",
+        )
+        .expect("spi");
+        assert!(empty.is_none());
+    }
+
+    #[pg_test]
+    fn test_s2_cell_to_wkt() {
+        let wkt = s2_cell_to_wkt(s2_cell_from_token("47a1cbd4"));
+        let body = wkt
+            .strip_prefix("POLYGON((")
+            .and_then(|rest| rest.strip_suffix("))"))
+            .expect("polygon wkt");
+        let coords: Vec<&str> = body.split(", ").collect();
+        assert_eq!(coords.len(), 5);
+        assert_eq!(coords[0], coords[4]);
+        let first: Vec<f64> = coords[0].split(' ').map(|c| c.parse().unwrap()).collect();
+        let vertex = s2_cell_to_vertices(s2_cell_from_token("47a1cbd4"))[0];
+        assert_eq!(first, vec![vertex.x, vertex.y]);
+    }
+
+    #[pg_test]
+    fn test_s2_cell_to_wkb() {
+        let cell = s2_cell_from_token("47a1cbd4");
+        let wkb = s2_cell_to_wkb(cell, false);
+        assert_eq!(wkb.len(), 1 + 4 + 4 + 4 + 5 * 16);
+        assert_eq!(wkb[0], 1);
+        assert_eq!(u32::from_le_bytes(wkb[1..5].try_into().unwrap()), 3);
+        assert_eq!(u32::from_le_bytes(wkb[9..13].try_into().unwrap()), 5);
+        let x = f64::from_le_bytes(wkb[13..21].try_into().unwrap());
+        assert_eq!(x, s2_cell_to_vertices(cell)[0].x);
+
+        let ewkb = s2_cell_to_wkb(cell, true);
+        assert_eq!(ewkb.len(), wkb.len() + 4);
+        assert_eq!(
+            u32::from_le_bytes(ewkb[1..5].try_into().unwrap()),
+            3 | 0x2000_0000
+        );
+        assert_eq!(u32::from_le_bytes(ewkb[5..9].try_into().unwrap()), 4326);
+        assert_eq!(&ewkb[9..], &wkb[5..]);
+    }
+
+    #[pg_test]
+    fn test_s2_cell_to_wkt_wkb_antimeridian() {
+        let cell = s2_cell_from_token("7");
+        let wkt = s2_cell_to_wkt(cell);
+        assert!(wkt.starts_with("MULTIPOLYGON(((") && wkt.ends_with(")))"), "{wkt}");
+        assert_eq!(wkt.matches("((").count(), 2);
+        let body = wkt.trim_start_matches("MULTIPOLYGON(").trim_end_matches(')');
+        for coord in body.split(", ") {
+            let lng = coord.trim_matches(['(', ')']).split(' ').next().unwrap();
+            let lng: f64 = lng.parse().unwrap();
+            assert!((-180.0..=180.0).contains(&lng), "{coord}");
+        }
+
+        let wkb = s2_cell_to_wkb(cell, true);
+        assert_eq!(
+            u32::from_le_bytes(wkb[1..5].try_into().unwrap()),
+            6 | 0x2000_0000
+        );
+        assert_eq!(u32::from_le_bytes(wkb[9..13].try_into().unwrap()), 2);
+        assert_eq!(u32::from_le_bytes(wkb[14..18].try_into().unwrap()), 3);
+
+        let got = Spi::get_one::<String>(
+            "SELECT s2_cells_to_multipolygon_wkt(ARRAY['7'::s2cellid, '47a1cbd4'::s2cellid])",
+        )
+        .expect("spi")
+        .expect("wkt");
+        assert_eq!(got.matches("((").count(), 3);
+    }
+
+    #[pg_test]
+    fn test_s2_cells_to_multipolygon_wkt_sql() {
+        let got = Spi::get_one::<String>(
+            "SELECT s2_cells_to_multipolygon_wkt(\
+                ARRAY['47a1cbd4'::s2cellid, NULL, '47a1cbdc'::s2cellid])",
+        )
+        .expect("spi")
+        .expect("wkt");
+        assert!(got.starts_with("MULTIPOLYGON(((") && got.ends_with(")))"));
+        assert_eq!(got.matches("((").count(), 2);
+
+        let empty = Spi::get_one::<String>(
+            "SELECT s2_cells_to_multipolygon_wkt(ARRAY[]::s2cellid[])",
+        )
+        .expect("spi");
+        assert_eq!(empty.as_deref(), Some("MULTIPOLYGON EMPTY"));
+    }
+
+    #[pg_test]
+    fn test_s2_cover_wkb_cell_polygon() {
+        let got = Spi::get_one::<bool>(
+            "SELECT bool_or(c = '47a1cbd4'::s2cellid) AND count(*) <= 9 \
+             FROM s2_cover_wkb(s2_cell_to_wkb('47a1cbd4'::s2cellid, true), 13) AS c",
+        )
+        .expect("spi");
+        assert_eq!(got, Some(true));
+    }
+
+    #[pg_test]
+    fn test_lng_lat_polygons_hole() {
+        let square = |lo: f64, hi: f64| {
+            vec![
+                Point { x: lo, y: lo },
+                Point { x: hi, y: lo },
+                Point { x: hi, y: hi },
+                Point { x: lo, y: hi },
+            ]
+        };
+        let region = LngLatPolygons::new(vec![vec![square(0.0, 10.0), square(4.0, 6.0)]])
+            .expect("polygon");
+        assert!(region.contains_point(1.0, 1.0));
+        assert!(!region.contains_point(5.0, 5.0));
+
+        let covering = fixed_level_coverer(8, 500).covering(&region).0;
+        let in_hole = CellID::from(LatLng::from_degrees(5.0, 5.0)).parent(8);
+        let in_shell = CellID::from(LatLng::from_degrees(1.0, 1.0)).parent(8);
+        assert!(!covering.contains(&in_hole));
+        assert!(covering.contains(&in_shell));
+    }
+
+    #[pg_test]
+    #[should_panic(expected = "invalid polygon wkb")]
+    fn test_s2_cover_wkb_invalid() {
+        Spi::run("SELECT * FROM s2_cover_wkb('\\x0101000000'::bytea, 10)").expect("spi");
+    }
+
+    #[pg_test]
+    fn test_parse_wkb_rejects_nested_multipolygon() {
+        let polygon = s2_cell_to_wkb(s2_cell_from_token("47a1cbd4"), false);
+        let multi = |depth: usize| {
+            let mut bytes = Vec::new();
+            for _ in 0..depth {
+                bytes.push(1u8);
+                bytes.extend_from_slice(&WKB_MULTIPOLYGON.to_le_bytes());
+                bytes.extend_from_slice(&1u32.to_le_bytes());
+            }
+            bytes.extend_from_slice(&polygon);
+            bytes
+        };
+        assert!(parse_wkb_polygons(&multi(1)).is_some());
+        assert!(parse_wkb_polygons(&multi(2)).is_none());
+        assert!(parse_wkb_polygons(&multi(100_000)).is_none());
+    }
+
+    #[pg_test]
+    #[should_panic(expected = "invalid polygon wkb")]
+    fn test_s2_cover_wkb_nested_multipolygon() {
+        Spi::run(
+            "SELECT * FROM s2_cover_wkb(\
+                decode(repeat('010600000001000000', 50000), 'hex') \
+                    || s2_cell_to_wkb('47a1cbd4'::s2cellid), 10)",
+        )
+        .expect("spi");
+    }
+
+    #[pg_test]
+    fn test_s2_cover_wkt_hole() {
+        let wkt = "POLYGON((0 0, 10 0, 10 10, 0 10, 0 0), (4 4, 6 4, 6 6, 4 6, 4 4))";
+        let query = format!(
+            "SELECT bool_or(c = s2_lat_lng_to_cell(point(1, 1), 8)) \
+                AND NOT bool_or(c = s2_lat_lng_to_cell(point(5, 5), 8)) \
+             FROM s2_cover_wkt('{wkt}', 8, 500) AS c"
+        );
+        let got = Spi::get_one::<bool>(&query).expect("spi");
+        assert_eq!(got, Some(true));
+    }
+
+    #[pg_test]
+    fn test_s2_cover_geojson_matches_wkt() {
+        let same = Spi::get_one::<bool>(
+            "SELECT array_agg(g ORDER BY g) = (\
+                SELECT array_agg(w ORDER BY w) \
+                FROM s2_cover_wkt('SRID=4326;MULTIPOLYGON(((0 0, 1 0, 1 1, 0 0)), ((5 5, 6 5, 6 6, 5 5)))', 10, 50) AS w) \
+             FROM s2_cover_geojson('{\"type\": \"Feature\", \"properties\": {}, \"geometry\": \
+                {\"type\": \"MultiPolygon\", \"coordinates\": \
+                [[[[0, 0], [1, 0], [1, 1], [0, 0]]], [[[5, 5], [6, 5], [6, 6], [5, 5]]]]}}'::json, 10, 50) AS g",
+        )
+        .expect("spi");
+        assert_eq!(same, Some(true));
+    }
+
+    #[pg_test]
+    fn test_s2_cover_wkt_ranges_merged() {
+        let wkt = "POLYGON((0 0, 1 0, 1 1, 0 0))";
+        let query = format!(
+            "SELECT (SELECT count(*) FROM s2_cover_wkt_ranges('{wkt}', 10, 50)) \
+                    < (SELECT count(*) FROM s2_cover_wkt('{wkt}', 10, 50)) \
+                AND bool_and(EXISTS (\
+                    SELECT 1 FROM s2_cover_wkt_ranges('{wkt}', 10, 50) AS r \
+                    WHERE r @> s2_cell_to_bigint(s2_cell_range_min(c)) \
+                      AND r @> s2_cell_to_bigint(s2_cell_range_max(c)))) \
+             FROM s2_cover_wkt('{wkt}', 10, 50) AS c"
+        );
+        let got = Spi::get_one::<bool>(&query).expect("spi");
+        assert_eq!(got, Some(true));
+    }
+
+    #[pg_test]
+    fn test_s2_cover_range_array_is_merged() {
+        let got = Spi::get_one::<bool>(
+            "WITH a AS (SELECT s2_cover_cap_range_array(point(2.35, 48.85), 5000, 14, 32) AS r) \
+             SELECT cardinality(r) < \
+                    (SELECT count(*) FROM s2_cover_cap(point(2.35, 48.85), 5000, 14, 32)) \
+                AND s2_lat_lng_to_cell(point(2.35, 48.85), 30)::bigint <@ ANY (r) \
+                AND NOT s2_lat_lng_to_cell(point(2.5, 48.85), 30)::bigint <@ ANY (r) \
+             FROM a",
+        )
+        .expect("spi");
+        assert_eq!(got, Some(true));
+        let got = Spi::get_one::<bool>(
+            "SELECT s2_lat_lng_to_cell(point(139.75, 35.68), 30)::bigint <@ ANY ( \
+                s2_cover_rect_range_array(box(point(139.70, 35.65), point(139.80, 35.72)), 12))",
+        )
+        .expect("spi");
+        assert_eq!(got, Some(true));
+    }
+
+    #[cfg(not(feature = "pg13"))]
+    #[pg_test]
+    fn test_s2_cover_multirange_matches_ranges() {
+        let got = Spi::get_one::<bool>(
+            "SELECT s2_cover_cap_multirange(point(2.35, 48.85), 5000, 14, 32) = \
+                 (SELECT range_agg(r) FROM s2_cover_cap_ranges(point(2.35, 48.85), 5000, 14, 32) r) \
+                AND s2_lat_lng_to_cell(point(2.35, 48.85), 30)::bigint \
+                    <@ s2_cover_cap_multirange(point(2.35, 48.85), 5000, 14) \
+                AND s2_cover_rect_multirange(box(point(139.70, 35.65), point(139.80, 35.72)), 12) = \
+                 (SELECT range_agg(r) \
+                  FROM s2_cover_rect_ranges(box(point(139.70, 35.65), point(139.80, 35.72)), 12) r)",
+        )
+        .expect("spi");
+        assert_eq!(got, Some(true));
+    }
+
+    #[pg_test]
+    fn test_s2cellrange_operators() {
+        let got = Spi::get_one::<String>("SELECT s2_cell_to_range('89c25'::s2cellid)::text")
+            .expect("spi");
+        assert_eq!(got.as_deref(), Some("[89c2400000000001,89c25fffffffffff]"));
+        let got = Spi::get_one::<bool>(
+            "SELECT s2_cell_to_range('89c25') @> '89c2584'::s2cellid \
+                AND s2_cell_to_range('89c25') @> s2_cell_to_range('89c2584') \
+                AND NOT s2_cell_to_range('89c23') && s2_cell_to_range('89c25') \
+                AND s2_cell_to_range('89c23') << s2_cell_to_range('89c25')",
+        )
+        .expect("spi");
+        assert_eq!(got, Some(true));
+    }
+
+    #[pg_test]
+    fn test_s2cellrange_gist_and_spgist_indexes() {
+        Spi::run(
+            "CREATE TABLE zone_ranges AS \
+             SELECT g AS id, \
+                    s2_cell_to_range(s2_lat_lng_to_cell(point(g * 0.01, 0), 9 + g % 8)) AS r \
+             FROM generate_series(0, 500) g; \
+             ANALYZE zone_ranges",
+        )
+        .expect("spi");
+        let probe = "s2_lat_lng_to_cell(point(2.503, 0), 30)";
+        let expected = Spi::get_one::<i64>(&format!(
+            "SELECT count(*) FROM zone_ranges WHERE {probe} BETWEEN lower(r) AND upper(r)"
         ))
         .expect("spi");
-        assert_eq!(got, Some(expected));
+        assert!(expected.unwrap_or(0) > 0);
+        Spi::run("SET enable_seqscan = off").expect("spi");
+        let query = format!("SELECT count(*) FROM zone_ranges WHERE r @> {probe}");
+        for method in ["gist", "spgist"] {
+            Spi::run(&format!(
+                "DROP INDEX IF EXISTS zone_ranges_idx; \
+                 CREATE INDEX zone_ranges_idx ON zone_ranges USING {method} (r)"
+            ))
+            .expect("spi");
+            let plan = Spi::explain(&query).expect("explain");
+            assert!(plan.0.to_string().contains("zone_ranges_idx"), "{method}");
+            assert_eq!(Spi::get_one::<i64>(&query).expect("spi"), expected, "{method}");
+        }
+        Spi::run("RESET enable_seqscan").expect("spi");
     }
 
     #[pg_test]
-    fn test_s2_cell_token_roundtrip() {
-        let token = "47a1cbd595522b39";
-        let cell = s2_cell_from_token(token);
-        let back = s2_cell_to_token(cell);
-        assert_eq!(back, token);
+    fn test_merged_cell_ranges_joins_siblings() {
+        let parent = CellID::from_token("47a1cbd4");
+        let merged = merged_cell_ranges(&parent.children());
+        assert_eq!(
+            merged,
+            vec![(
+                u64_to_i64_norm(parent.range_min().0),
+                u64_to_i64_norm(parent.range_max().0)
+            )]
+        );
     }
 
     #[pg_test]
-    fn test_s2_cell_token_roundtrip_high_bit() {
-        let token = "b112966aaaaaaaab";
-        let cell = s2_cell_from_token(token);
-        let back = s2_cell_to_token(cell);
-        assert_eq!(back, token);
+    #[should_panic(expected = "invalid polygon wkt")]
+    fn test_s2_cover_wkt_invalid() {
+        Spi::run("SELECT * FROM s2_cover_wkt('LINESTRING(0 0, 1 1)', 10)").expect("spi");
     }
 
     #[pg_test]
-    #[should_panic(expected = "invalid s2cellid token")]
-    fn test_s2_cell_from_token_invalid() {
-        let _ = s2_cell_from_token("zz");
+    fn test_s2_cover_polyline_sql() {
+        let got = Spi::get_one::<bool>(
+            "WITH route AS (SELECT '[(139.70,35.68),(139.77,35.68),(139.77,35.70)]'::path AS p) \
+             SELECT bool_or(c = s2_lat_lng_to_cell(point(139.70, 35.68), 14)) \
+                AND bool_or(c = s2_lat_lng_to_cell(point(139.735, 35.68), 14)) \
+                AND bool_or(c = s2_lat_lng_to_cell(point(139.77, 35.70), 14)) \
+                AND NOT bool_or(c = s2_lat_lng_to_cell(point(139.735, 35.683), 14)) \
+             FROM route, LATERAL s2_cover_polyline(p, 14) AS c",
+        )
+        .expect("spi");
+        assert_eq!(got, Some(true));
+    }
+
+    #[pg_test]
+    fn test_s2_cover_polyline_buffer_sql() {
+        let got = Spi::get_one::<bool>(
+            "WITH route AS (SELECT '[(139.70,35.68),(139.77,35.68)]'::path AS p), \
+                  buffered AS (SELECT c FROM route, LATERAL s2_cover_polyline_buffer(p, 500, 14) AS c) \
+             SELECT (SELECT bool_and(c IN (SELECT c FROM buffered)) \
+                     FROM route, LATERAL s2_cover_polyline(p, 14) AS c) \
+                AND (SELECT bool_or(c = s2_lat_lng_to_cell(point(139.735, 35.683), 14)) FROM buffered) \
+                AND (SELECT count(*) FROM route, LATERAL s2_cover_polyline_buffer_ranges(p, 500, 14)) \
+                    < (SELECT count(*) FROM buffered)",
+        )
+        .expect("spi");
+        assert_eq!(got, Some(true));
+    }
+
+    #[pg_test]
+    #[should_panic(expected = "invalid path")]
+    fn test_s2_cover_polyline_invalid() {
+        Spi::run("SELECT * FROM s2_cover_polyline('[(0,0),(10,100)]'::path, 10)").expect("spi");
+    }
+
+    #[pg_test]
+    fn test_s2_polygon_area_matches_cell() {
+        let cell = Cell::from(CellID::from_token("89c25"));
+        let sr = Spi::get_one::<f64>(
+            "SELECT s2_polygon_area(s2_cell_to_boundary('89c25'::s2cellid), 'sr')",
+        )
+        .expect("spi")
+        .expect("area");
+        assert!((sr - cell.exact_area()).abs() < 1e-9 * cell.exact_area());
+
+        let reversed = Spi::get_one::<f64>(
+            "SELECT s2_polygon_area(('(' || array_to_string(ARRAY(\
+                SELECT v::text \
+                FROM unnest(s2_cell_to_vertices('89c25'::s2cellid)) WITH ORDINALITY AS t(v, n) \
+                ORDER BY n DESC), ',') || ')')::polygon, 'sr')",
+        )
+        .expect("spi")
+        .expect("area");
+        assert!((reversed - sr).abs() < 1e-12);
+
+        let m2 = Spi::get_one::<f64>(
+            "SELECT s2_polygon_area(s2_cell_to_boundary('89c25'::s2cellid))",
+        )
+        .expect("spi")
+        .expect("area");
+        let radius = EARTH_RADIUS_M_DEFAULT;
+        assert!((m2 - sr * radius * radius).abs() < 1e-6 * m2);
+    }
+
+    #[pg_test]
+    fn test_s2_polygon_contains_and_centroid() {
+        let got = Spi::get_one::<bool>(
+            "WITH p AS (SELECT s2_cell_to_boundary('47a1cbd4'::s2cellid) AS poly) \
+             SELECT s2_polygon_contains(poly, s2_cell_to_lat_lng('47a1cbd4'::s2cellid)) \
+                AND NOT s2_polygon_contains(poly, s2_cell_to_lat_lng('47a1cbdc'::s2cellid)) \
+                AND s2_lat_lng_to_cell(s2_polygon_centroid(poly), 13) = '47a1cbd4'::s2cellid \
+             FROM p",
+        )
+        .expect("spi");
+        assert_eq!(got, Some(true));
+    }
+
+    #[pg_test]
+    fn test_spherical_loop_crossing_through_vertex() {
+        let v = |x, y, z| S2Point(Vector::new(x, y, z));
+        // The arc a-b runs along the equator (z = 0) and passes exactly
+        // through the first vertex of each loop.
+        let (a, b) = (v(1.0, 0.0, 0.0), v(1.0, 1.0, 0.0));
+        let through = SphericalLoop {
+            vertices: vec![v(1.0, 0.5, 0.0), v(1.0, 0.7, 0.3), v(1.0, 0.2, -0.3)],
+            area: 0.0,
+        };
+        let touching = SphericalLoop {
+            vertices: vec![v(1.0, 0.5, 0.0), v(1.0, 0.7, 0.3), v(1.0, 0.3, 0.3)],
+            area: 0.0,
+        };
+        assert!(!through.crosses_odd(&a, &b));
+        assert!(through.crosses_odd(&a, &v(1.0, 0.47, 0.0)));
+        assert!(!touching.crosses_odd(&a, &b));
+        assert!(!touching.crosses_odd(&a, &v(1.0, 0.47, 0.0)));
+    }
+
+    #[pg_test]
+    fn test_spherical_loop_contains_centimeter_polygons() {
+        // Offsets in meters from a point in Paris.
+        let (lng, lat): (f64, f64) = (2.35, 48.85);
+        let (dy, dx) = (1.0 / 111_320.0, 1.0 / (111_320.0 * lat.to_radians().cos()));
+        let at = |x: f64, y: f64| Point {
+            x: lng + x * dx,
+            y: lat + y * dy,
+        };
+        let inside = |l: &SphericalLoop, x, y| l.contains(&S2Point::from(valid_latlng(at(x, y))));
+
+        let square = [at(0.0, 0.0), at(0.01, 0.0), at(0.01, 0.01), at(0.0, 0.01)];
+        let square = SphericalLoop::from_points(&square).expect("square");
+        assert!(inside(&square, 0.005, 0.005) && inside(&square, 0.009, 0.001));
+        assert!(!inside(&square, 0.02, 0.005) && !inside(&square, 0.005, -0.001));
+
+        // A 1 m by 2 mm sliver: narrower than any fixed reference offset.
+        let sliver = [at(0.0, 0.0), at(1.0, 0.0), at(1.0, 0.002), at(0.0, 0.002)];
+        let sliver = SphericalLoop::from_points(&sliver).expect("sliver");
+        assert!(inside(&sliver, 0.5, 0.001) && inside(&sliver, 0.01, 0.0015));
+        assert!(!inside(&sliver, 0.5, 0.004) && !inside(&sliver, 1.5, 0.001));
+    }
+
+    #[pg_test]
+    #[should_panic(expected = "invalid unit")]
+    fn test_s2_polygon_area_invalid_unit() {
+        Spi::run("SELECT s2_polygon_area('((0,0),(1,0),(1,1))'::polygon, 'acre')").expect("spi");
+    }
+
+    #[pg_test]
+    fn test_s2cap_s2latlngrect_text_roundtrip() {
+        let cap = Spi::get_one::<String>("SELECT '< (10.5, -20), 1.25 >'::s2cap::text")
+            .expect("spi");
+        assert_eq!(cap.as_deref(), Some("<(10.5,-20),1.25>"));
+        let rect = Spi::get_one::<String>("SELECT '((170,-10),(-170,10))'::s2latlngrect::text")
+            .expect("spi");
+        assert_eq!(rect.as_deref(), Some("((170,-10),(-170,10))"));
+    }
+
+    #[pg_test]
+    fn test_s2_cap_contains_union_and_bound() {
+        let got = Spi::get_one::<bool>(
+            "WITH c AS (SELECT s2_cap(point(179.9, 0), 50000) AS cap) \
+             SELECT s2_cap_contains(cap, point(-179.9, 0)) \
+                AND NOT s2_cap_contains(cap, point(0, 0)) \
+                AND s2_latlngrect_contains(s2_cap_rect_bound(cap), point(180, 0.4)) \
+                AND NOT s2_latlngrect_contains(s2_cap_rect_bound(cap), point(179, 0)) \
+                AND s2_cap_contains(s2_cap_union(cap, s2_cap(point(10, 0), 1000)), point(10, 0)) \
+                AND s2_cap_contains(s2_cap_union(s2_cap(point(10, 0), 1000), cap), point(-179.9, 0)) \
+                AND s2_cap_contains(s2_cap_expanded(cap, 1000000), point(175, 0)) \
+             FROM c",
+        )
+        .expect("spi");
+        assert_eq!(got, Some(true));
+
+        let radius = Spi::get_one::<f64>("SELECT s2_cap_radius_m(s2_cap(point(0, 0), 1234.5))")
+            .expect("spi")
+            .expect("radius");
+        assert!((radius - 1234.5).abs() < 1e-6);
+    }
+
+    #[pg_test]
+    fn test_s2_latlngrect_antimeridian_and_expanded() {
+        let got = Spi::get_one::<bool>(
+            "WITH r AS (SELECT '((170,-10),(-170,10))'::s2latlngrect AS rect) \
+             SELECT s2_latlngrect_contains(rect, point(180, 0)) \
+                AND NOT s2_latlngrect_contains(rect, point(0, 0)) \
+                AND s2_latlngrect_intersects_cell(rect, s2_lat_lng_to_cell(point(-175, 5), 10)) \
+                AND NOT s2_latlngrect_intersects_cell(rect, s2_lat_lng_to_cell(point(0, 0), 10)) \
+                AND s2_latlngrect_contains(s2_latlngrect_expanded(rect, 200000), point(180, 11.5)) \
+                AND s2_latlngrect_contains(s2_latlngrect_union(rect, s2_latlngrect(box(point(0, 0), point(1, 1)))), point(0.5, 0.5)) \
+                AND s2_cap_contains(s2_latlngrect_cap_bound(rect), point(170, -10)) \
+             FROM r",
+        )
+        .expect("spi");
+        assert_eq!(got, Some(true));
+    }
+
+    #[pg_test]
+    fn test_s2_cover_cap_accepts_s2cap() {
+        let got = Spi::get_one::<bool>(
+            "SELECT array_agg(c ORDER BY c) = \
+                (SELECT array_agg(c ORDER BY c) FROM s2_cover_cap(point(2.35, 48.85), 5000, 12) c) \
+             FROM s2_cover_cap(s2_cap(point(2.35, 48.85), 5000), 12) c",
+        )
+        .expect("spi");
+        assert_eq!(got, Some(true));
+        let ranges = Spi::get_one::<i64>(
+            "SELECT count(*) FROM s2_cover_rect_ranges('((170,-1),(-170,1))'::s2latlngrect, 6, 16)",
+        )
+        .expect("spi")
+        .expect("count");
+        assert!(ranges > 0);
+    }
+
+    #[pg_test]
+    fn test_s2_cell_rect_bound_antimeridian() {
+        // Level-8 cell whose western edge is at 179.7° and eastern edge at 180°.
+        let got = Spi::get_one::<bool>(
+            "WITH c AS (SELECT s2_lat_lng_to_cell(point(179.99, 10), 8) AS cell) \
+             SELECT s2_latlngrect_contains(s2_cell_rect_bound(cell), point(179.99, 10)) \
+                AND NOT s2_latlngrect_contains(s2_cell_rect_bound(cell), point(0, 10)) \
+                AND NOT s2_latlngrect_contains(s2_cell_rect_bound(cell), point(-179.9, 10)) \
+                AND s2_latlngrect_contains(s2_cell_rect_bound(cell, 20000), point(-179.9, 10)) \
+                AND NOT s2_latlngrect_contains(s2_cell_rect_bound(cell, 20000), point(0, 10)) \
+             FROM c",
+        )
+        .expect("spi");
+        assert_eq!(got, Some(true));
+
+        let polar = Spi::get_one::<String>(
+            "SELECT s2_cell_rect_bound(s2_lat_lng_to_cell(point(0, 89.99), 3))::text",
+        )
+        .expect("spi")
+        .expect("rect");
+        assert!(polar.starts_with("((-180,"), "{polar}");
+        assert!(polar.ends_with("),(180,90))"), "{polar}");
+    }
+
+    #[pg_test]
+    fn test_s2_cap_bbox_and_rect_expand() {
+        let bbox = Spi::get_one::<BOX>("SELECT s2_cap_bbox(point(10, 60), 111195)")
+            .expect("spi")
+            .expect("box");
+        // 1° of arc: latitude grows by 1°, longitude by ~2° at 60°N.
+        assert!((bbox.low.y - 59.0).abs() < 1e-3 && (bbox.high.y - 61.0).abs() < 1e-3);
+        assert!(bbox.low.x < 8.0 && bbox.low.x > 7.9 && bbox.high.x > 12.0 && bbox.high.x < 12.1);
+
+        let polar = Spi::get_one::<BOX>("SELECT s2_cap_bbox(point(10, 89.5), 111195)")
+            .expect("spi")
+            .expect("box");
+        assert_eq!((polar.low.x, polar.high.x, polar.high.y), (-180.0, 180.0, 90.0));
+
+        let wrap = Spi::get_one::<BOX>("SELECT s2_cap_bbox(point(179.9, 0), 50000)")
+            .expect("spi")
+            .expect("box");
+        assert_eq!((wrap.low.x, wrap.high.x), (-180.0, 180.0));
+
+        let expanded = Spi::get_one::<BOX>(
+            "SELECT s2_rect_expand(box(point(10, 59), point(11, 60)), 111195)",
+        )
+        .expect("spi")
+        .expect("box");
+        assert!((expanded.low.y - 58.0).abs() < 1e-3 && (expanded.high.y - 61.0).abs() < 1e-3);
+        assert!(expanded.low.x < 9.0 && expanded.high.x > 12.0);
     }
 
-    #[pg_test]
-    fn test_s2_cell_to_bigint() {
-        let token = "47a1cbd595522b39";
-        let cell = s2_cell_from_token(token);
-        let expected = u64_to_i64_norm(CellID::from_token(token).0);
-        assert_eq!(s2_cell_to_bigint(cell), expected);
+    #[pg_test]
+    fn test_s2_destination_point_and_bearing() {
+        let one_degree_m = EARTH_RADIUS_M_DEFAULT.to_radians();
+        let east = s2_destination_point(Point { x: 179.5, y: 0.0 }, 90.0, one_degree_m);
+        assert!((east.x - -179.5).abs() < 1e-9 && east.y.abs() < 1e-9);
+        let north = s2_destination_point(Point { x: 10.0, y: 60.0 }, 0.0, one_degree_m);
+        assert!((north.x - 10.0).abs() < 1e-9 && (north.y - 61.0).abs() < 1e-9);
+
+        let paris = Point { x: 2.35, y: 48.85 };
+        let tokyo = Point { x: 139.77, y: 35.68 };
+        assert!((s2_initial_bearing(paris, tokyo) - 33.38).abs() < 0.01);
+        assert_eq!(s2_initial_bearing(Point { x: 0.0, y: 0.0 }, Point { x: -10.0, y: 0.0 }), 270.0);
+
+        let moved = s2_destination_point(paris, 45.0, 500.0);
+        assert!((s2_initial_bearing(paris, moved) - 45.0).abs() < 1e-6);
+        assert!((s2_great_circle_distance(paris, moved, "m") - 500.0).abs() < 1e-6);
     }
 
     #[pg_test]
-    fn test_s2_cell_from_bigint_roundtrip() {
-        let token = "47a1cbd595522b39";
-        let cell = s2_cell_from_token(token);
-        let id = s2_cell_to_bigint(cell);
-        let back = s2_cell_from_bigint(id);
-        assert_eq!(s2_cell_to_token(back), token);
+    fn test_s2_interpolate_and_midpoint() {
+        let mid = s2_midpoint(Point { x: 170.0, y: 0.0 }, Point { x: -170.0, y: 0.0 });
+        assert!((mid.x.abs() - 180.0).abs() < 1e-9 && mid.y.abs() < 1e-9);
+        let beyond = s2_interpolate(Point { x: 0.0, y: 0.0 }, Point { x: 10.0, y: 0.0 }, 1.5);
+        assert!((beyond.x - 15.0).abs() < 1e-9);
+        let got = Spi::get_one::<bool>(
+            "SELECT s2_interpolate(point(0, 0), point(0, 10), 0.25) ~= point(0, 2.5) \
+                AND s2_midpoint(point(0, 0), point(0, 10)) ~= point(0, 5)",
+        )
+        .expect("spi");
+        assert_eq!(got, Some(true));
     }
 
     #[pg_test]
-    fn test_s2_is_valid_cell() {
-        let valid = s2_cell_from_token("47a1cbd595522b39");
-        assert!(s2_is_valid_cell(valid));
+    fn test_s2_point_aggregates_antimeridian() {
+        let values =
+            "(VALUES (point(179, 0)), (point(-179, 1)), (point(178.5, -1)), (NULL::point)) AS v(p)";
+        let centroid = Spi::get_one::<Point>(&format!("SELECT s2_centroid_agg(p) FROM {values}"))
+            .expect("spi")
+            .expect("centroid");
+        assert!(centroid.x.abs() > 179.0 && centroid.y.abs() < 0.5, "{centroid:?}");
 
-        let valid_high_bit = s2_cell_from_token("b112966aaaaaaaab");
-        assert!(s2_is_valid_cell(valid_high_bit));
+        let rect =
+            Spi::get_one::<String>(&format!("SELECT s2_latlngrect_agg(p)::text FROM {values}"))
+                .expect("spi")
+                .expect("rect");
+        assert!(rect.starts_with("((178.5") && rect.contains("),(-179,"), "{rect}");
+        let bbox = Spi::get_one::<BOX>(&format!("SELECT s2_bounding_rect_agg(p) FROM {values}"))
+            .expect("spi")
+            .expect("box");
+        assert_eq!((bbox.low.x, bbox.high.x), (-180.0, 180.0));
+        assert!((bbox.low.y - -1.0).abs() < 1e-9 && (bbox.high.y - 1.0).abs() < 1e-9);
 
-        let invalid = s2_cell_from_bigint(0);
-        assert!(!s2_is_valid_cell(invalid));
+        let got = Spi::get_one::<bool>(&format!(
+            "WITH c AS (SELECT s2_cap_expanded(s2_bounding_cap_agg(p), 0.001) AS cap FROM {values}) \
+             SELECT s2_cap_contains(cap, point(179, 0)) \
+                AND s2_cap_contains(cap, point(-179, 1)) \
+                AND s2_cap_contains(cap, point(178.5, -1)) \
+                AND s2_cap_radius_m(cap) < 200000 \
+             FROM c"
+        ))
+        .expect("spi");
+        assert_eq!(got, Some(true));
+
+        let small = Spi::get_one::<BOX>(
+            "SELECT s2_bounding_rect_agg(p) FROM (VALUES (point(10, 20)), (point(12, 21))) AS v(p)",
+        )
+        .expect("spi")
+        .expect("box");
+        assert_eq!((small.low.x, small.high.x), (10.0, 12.0));
     }
 
     #[pg_test]
-    fn test_s2_get_level_and_face() {
-        let face0 = s2_cell_from_token("1");
-        assert_eq!(s2_get_level(face0), 0);
-        assert_eq!(s2_get_face(face0), 0);
+    fn test_s2_point_aggregates_empty_and_parallel_combine() {
+        let empty = Spi::get_one::<bool>(
+            "SELECT s2_centroid_agg(p) IS NULL AND s2_bounding_cap_agg(p) IS NULL \
+                AND s2_bounding_rect_agg(p) IS NULL AND s2_latlngrect_agg(p) IS NULL \
+             FROM (SELECT NULL::point AS p WHERE false) v",
+        )
+        .expect("spi");
+        assert_eq!(empty, Some(true));
 
-        let face1 = s2_cell_from_token("3");
-        assert_eq!(s2_get_level(face1), 0);
-        assert_eq!(s2_get_face(face1), 1);
+        let west = rect_add_latlng(&[], Point { x: 179.0, y: 0.0 });
+        let west = rect_add_latlng(&west, Point { x: 178.0, y: 1.0 });
+        let east = rect_add_latlng(&[], Point { x: -179.0, y: -1.0 });
+        let combined = rect_from_state(&west).union(&rect_from_state(&east));
+        let combined = rect_from_state(&rect_state(&combined));
+        assert!(combined.is_inverted());
+        assert!((combined.lng_lo().deg() - 178.0).abs() < 1e-9);
+        assert!((combined.lng_hi().deg() - -179.0).abs() < 1e-9);
+        assert!(rect_from_state(&[]).is_empty() && cap_from_state(&[]).is_empty());
     }
 
     #[pg_test]
-    fn test_s2_lat_lng_to_cell_level() {
-        let lat = 49.703498679;
-        let lng = 11.770681595;
-        let level = 12i32;
-        let ll = LatLng::from_degrees(lat, lng);
-        let expected = CellID::from(ll).parent(level as u64).to_token();
+    fn test_s2_cell_histogram_agg() {
+        let got = Spi::get_one::<JsonB>(
+            "SELECT s2_cell_histogram(c, 4) FROM unnest(ARRAY[\
+                s2_lat_lng_to_cell(point(2.35, 48.85), 30), \
+                s2_lat_lng_to_cell(point(2.36, 48.86), 20), \
+                s2_lat_lng_to_cell(point(139.77, 35.68), 30), \
+                NULL]) AS c",
+        )
+        .expect("spi")
+        .expect("histogram");
+        let paris = s2_cell_to_token(s2_lat_lng_to_cell(Point { x: 2.35, y: 48.85 }, 4));
+        let tokyo = s2_cell_to_token(s2_lat_lng_to_cell(Point { x: 139.77, y: 35.68 }, 4));
+        assert_eq!(got.0, json!({ paris: 2, tokyo: 1 }));
 
-        let got = s2_lat_lng_to_cell(Point { x: lng, y: lat }, level);
-        assert_eq!(s2_cell_to_token(got), expected);
+        let empty = Spi::get_one::<JsonB>(
+            "SELECT s2_cell_histogram(c, 4) FROM (SELECT NULL::s2cellid AS c WHERE false) v",
+        )
+        .expect("spi");
+        assert!(empty.is_none());
     }
 
     #[pg_test]
-    #[should_panic(expected = "invalid level")]
-    fn test_s2_lat_lng_to_cell_level_invalid() {
-        let _ = s2_lat_lng_to_cell(Point { x: 0.0, y: 0.0 }, 31);
+    fn test_s2_cell_histogram_rollup_matches_group_by() {
+        let got = Spi::get_one::<bool>(
+            "WITH cells AS ( \
+                SELECT s2_lat_lng_to_cell(point(2 + g * 0.01, 48 + g * 0.003), 30) AS c \
+                FROM generate_series(1, 200) g \
+             ), expected AS ( \
+                SELECT l AS level, s2_cell_to_parent(c, l) AS cell, count(*) AS count \
+                FROM cells, unnest(ARRAY[6, 10, 14]) AS l GROUP BY 1, 2 \
+             ), rollup AS ( \
+                SELECT l.key::int AS level, c.key::s2cellid AS cell, c.value::bigint AS count \
+                FROM (SELECT s2_cell_histogram_rollup(c, ARRAY[14, 6, 10, 10]) AS r FROM cells) h, \
+                     jsonb_each(h.r) l, jsonb_each_text(l.value) c \
+             ) \
+             SELECT NOT EXISTS (SELECT * FROM expected EXCEPT SELECT * FROM rollup) \
+                AND NOT EXISTS (SELECT * FROM rollup EXCEPT SELECT * FROM expected)",
+        )
+        .expect("spi");
+        assert_eq!(got, Some(true));
     }
 
     #[pg_test]
-    fn test_s2_lat_lng_to_cell_default_level() {
-        let lat = 49.703498679;
-        let lng = 11.770681595;
-        let expected = s2_lat_lng_to_cell(Point { x: lng, y: lat }, 14);
-
-        let got = s2_lat_lng_to_cell_default(Point { x: lng, y: lat });
-        assert_eq!(s2_cell_to_token(got), s2_cell_to_token(expected));
+    #[should_panic(expected = "invalid level")]
+    fn test_s2_cell_histogram_rollup_too_fine() {
+        Spi::run(
+            "SELECT s2_cell_histogram_rollup(c, ARRAY[12]) FROM (VALUES ('89c25'::s2cellid)) v(c)",
+        )
+        .expect("spi");
     }
 
     #[pg_test]
-    fn test_s2_lat_lng_to_cell_default_level_guc() {
-        let lat = 49.703498679;
-        let lng = 11.770681595;
-        Spi::run("SET pg_s2.default_level = 10").expect("set GUC");
+    fn test_s2_cell_histogram_parallel() {
+        Spi::run(
+            "CREATE TABLE histogram_cells AS \
+             SELECT s2_lat_lng_to_cell(point(2 + (g % 97) * 0.05, 48 + (g % 89) * 0.02), 30) AS c \
+             FROM generate_series(1, 2000) g",
+        )
+        .expect("spi");
+        let queries = [
+            "SELECT s2_cell_histogram(c, 8) FROM histogram_cells",
+            "SELECT s2_cell_histogram_rollup(c, ARRAY[4, 8, 12]) FROM histogram_cells",
+        ];
+        let serial: Vec<_> = queries
+            .iter()
+            .map(|query| Spi::get_one::<JsonB>(query).expect("spi").expect("histogram").0)
+            .collect();
+        assert_eq!(serial[0], serial[1]["8"]);
 
-        let expected = s2_lat_lng_to_cell(Point { x: lng, y: lat }, 10);
-        let got = s2_lat_lng_to_cell_default(Point { x: lng, y: lat });
-        assert_eq!(s2_cell_to_token(got), s2_cell_to_token(expected));
+        Spi::run(
+            "SET LOCAL parallel_setup_cost = 0; SET LOCAL parallel_tuple_cost = 0; \
+             SET LOCAL min_parallel_table_scan_size = 0; \
+             SET LOCAL max_parallel_workers_per_gather = 2",
+        )
+        .expect("spi");
+        for (query, expected) in queries.iter().zip(&serial) {
+            let plan = Spi::explain(query).expect("explain");
+            assert!(plan.0.to_string().contains("\"Partial Mode\":\"Partial\""), "{query}");
+            let got = Spi::get_one::<JsonB>(query).expect("spi").expect("histogram");
+            assert_eq!(&got.0, expected, "{query}");
+        }
     }
 
     #[pg_test]
-    fn test_s2_cell_to_lat_lng() {
-        let cell = s2_cell_from_token("47a1cbd595522b39");
-        let ll = s2_cell_to_lat_lng(cell);
-        assert!((ll.y - 49.703498679).abs() < 1e-6);
-        assert!((ll.x - 11.770681595).abs() < 1e-6);
-    }
+    fn test_s2cellunion_text_io_and_casts() {
+        // The four children of 89c25 collapse into their parent.
+        let got = Spi::get_one::<String>(
+            "SELECT '{89c254, 89c25c,89c24c, 89c244, 47a1cbd4}'::s2cellunion::text",
+        )
+        .expect("spi");
+        assert_eq!(got.as_deref(), Some("{47a1cbd4,89c25}"));
+        let empty = Spi::get_one::<String>("SELECT '{}'::s2cellunion::text").expect("spi");
+        assert_eq!(empty.as_deref(), Some("{}"));
 
-    #[pg_test]
-    fn test_s2_cell_bbox() {
-        let token = "47a1cbd595522b39";
-        let cell = s2_cell_from_token(token);
-        let bbox = s2_cell_bbox(cell);
-        let rect = Cell::from(CellID::from_token(token)).rect_bound();
-        assert!((bbox.low.x - rect.lng_lo().deg()).abs() < 1e-12);
-        assert!((bbox.low.y - rect.lat_lo().deg()).abs() < 1e-12);
-        assert!((bbox.high.x - rect.lng_hi().deg()).abs() < 1e-12);
-        assert!((bbox.high.y - rect.lat_hi().deg()).abs() < 1e-12);
+        let got = Spi::get_one::<bool>(
+            "SELECT (ARRAY['89c25'::s2cellid, NULL, '89c254']::s2cellunion)::s2cellid[] \
+                    = ARRAY['89c25'::s2cellid] \
+                AND s2_cellunion_num_cells('{89c25,47a1cbd4,47a1cbdc}'::s2cellunion) = 3",
+        )
+        .expect("spi");
+        assert_eq!(got, Some(true));
     }
 
     #[pg_test]
-    #[should_panic(expected = "invalid s2cellid")]
-    fn test_s2_cell_to_lat_lng_invalid() {
-        let _ = s2_cell_to_lat_lng(s2_cell_from_bigint(0));
+    fn test_s2cellunion_contains_matches_cell_union() {
+        let cells: Vec<CellID> = (0..500)
+            .map(|i| {
+                let ll = LatLng::from_degrees(40.0 + (i as f64) * 0.037, (i as f64) * 0.041);
+                CellID::from(ll).parent(8 + (i % 15) as u64)
+            })
+            .collect();
+        let union = S2CellUnion::from_cells(cells.clone());
+        let mut reference = CellUnion(cells);
+        reference.normalize();
+        assert_eq!(union.cells(), reference.0);
+        assert!(union.bytes.len() < union.len() * 8);
+        for i in 0..2000 {
+            let ll = LatLng::from_degrees(39.0 + (i as f64) * 0.011, -1.0 + (i as f64) * 0.013);
+            let probe = CellID::from(ll).parent((i % 31) as u64);
+            assert_eq!(union.contains_cell(probe), reference.contains_cellid(&probe));
+            assert_eq!(union.intersects_cell(probe), reference.intersects_cellid(&probe));
+        }
+
+        let got = Spi::get_one::<bool>(
+            "SELECT '{89c25}'::s2cellunion @> '89c2584'::s2cellid \
+                AND NOT '{89c2584}'::s2cellunion @> '89c25'::s2cellid \
+                AND '{89c2584}'::s2cellunion && '89c25'::s2cellid \
+                AND NOT '{89c25}'::s2cellunion && '47a1cbd4'::s2cellid \
+                AND '89c2584'::s2cellid <@ '{89c25}'::s2cellunion \
+                AND NOT '89c25'::s2cellid <@ '{89c2584}'::s2cellunion \
+                AND '89c25'::s2cellid && '{89c2584}'::s2cellunion \
+                AND NOT '47a1cbd4'::s2cellid && '{89c25}'::s2cellunion",
+        )
+        .expect("spi");
+        assert_eq!(got, Some(true));
+
+        // The commutators let the planner flip the operands.
+        let commutator = Spi::get_one::<bool>(
+            "SELECT oprcom <> 0 FROM pg_operator \
+             WHERE oprname = '<@' AND oprleft = 's2cellid'::regtype \
+               AND oprright = 's2cellunion'::regtype",
+        )
+        .expect("spi");
+        assert_eq!(commutator, Some(true));
     }
 
     #[pg_test]
-    fn test_s2_cell_range_min() {
-        let token = "47a1cbd595522b39";
-        let cell = s2_cell_from_token(token);
-        let expected = CellID::from_token(token).range_min().to_token();
-        let got = s2_cell_range_min(cell);
-        assert_eq!(s2_cell_to_token(got), expected);
+    fn test_s2cellunion_contains_union() {
+        let owned = S2CellUnion::from_cells(vec![CellID::from_token("89c25")]);
+        let borrowed = S2CellUnion::from_datum_bytes(&owned.bytes);
+        assert!(matches!(borrowed.bytes, Cow::Borrowed(_)));
+        assert_eq!(borrowed, owned);
+
+        let got = Spi::get_one::<bool>(
+            "SELECT '{89c25,47a1cbd4}'::s2cellunion @> '{89c2584,89c25c,47a1cbd4}'::s2cellunion \
+                AND '{89c25}'::s2cellunion @> '{}'::s2cellunion \
+                AND '{89c25}'::s2cellunion @> '{89c25}'::s2cellunion \
+                AND NOT '{89c2584}'::s2cellunion @> '{89c25}'::s2cellunion \
+                AND NOT '{89c25}'::s2cellunion @> '{89c2584,47a1cbd4}'::s2cellunion",
+        )
+        .expect("spi");
+        assert_eq!(got, Some(true));
     }
 
     #[pg_test]
-    fn test_s2_cell_range_max() {
-        let token = "47a1cbd595522b39";
-        let cell = s2_cell_from_token(token);
-        let expected = CellID::from_token(token).range_max().to_token();
-        let got = s2_cell_range_max(cell);
-        assert_eq!(s2_cell_to_token(got), expected);
+    #[should_panic(expected = "invalid s2cellunion token")]
+    fn test_s2cellunion_invalid_token() {
+        Spi::run("SELECT '{89c25,zz}'::s2cellunion").expect("spi");
     }
 
     #[pg_test]
-    fn test_s2_cell_to_parent_level() {
-        let token = "47a1cbd595522b39";
-        let cell_raw = CellID::from_token(token);
-        assert!(cell_raw.level() > 0);
-        let level = cell_raw.level() as i32 - 1;
-        let expected = cell_raw.parent(level as u64).to_token();
-        let cell = s2_cell_from_token(token);
-        let got = s2_cell_to_parent(cell, level);
-        assert_eq!(s2_cell_to_token(got), expected);
+    fn test_s2_cell_ancestors() {
+        let got = Spi::get_one::<String>(
+            "SELECT string_agg(a::text, ',') \
+             FROM s2_cell_ancestors(s2_cell_from_token('89c2584'), 6, 12) a",
+        )
+        .expect("spi");
+        assert_eq!(got.as_deref(), Some("89c3,89c24,89c25,89c25c,89c259,89c2584"));
+        let got = Spi::get_one::<String>(
+            "SELECT string_agg(a::text, ',') \
+             FROM s2_cell_ancestors('89c2584'::s2cellid, ARRAY[11, 8, NULL, 8, 20, 0])",
+        )
+        .expect("spi");
+        assert_eq!(got.as_deref(), Some("9,89c25,89c2584"));
     }
 
     #[pg_test]
-    fn test_s2_cell_to_parent_default() {
-        let token = "47a1cbd595522b39";
-        let cell_raw = CellID::from_token(token);
-        assert!(cell_raw.level() > 0);
-        let expected = cell_raw.parent(cell_raw.level() - 1).to_token();
-        let cell = s2_cell_from_token(token);
-        let got = s2_cell_to_parent_default(cell);
-        assert_eq!(s2_cell_to_token(got), expected);
+    fn test_s2_cell_ancestors_hash_join_matches_range_join() {
+        Spi::run(
+            "CREATE TABLE regions AS \
+             SELECT DISTINCT s2_lat_lng_to_cell(point(-74 + g * 0.01, 40.7), 8 + g % 5) AS cell \
+             FROM generate_series(0, 60) g; \
+             CREATE TABLE pts AS \
+             SELECT g AS id, s2_lat_lng_to_cell(point(-74 + g * 0.0007, 40.7), 30) AS cell \
+             FROM generate_series(0, 900) g",
+        )
+        .expect("spi");
+        let got = Spi::get_one::<bool>(
+            "WITH lv AS (SELECT array_agg(DISTINCT s2_get_level(cell)) AS levels FROM regions), \
+             by_hash AS ( \
+                 SELECT p.id, r.cell FROM pts p, lv, s2_cell_ancestors(p.cell, lv.levels) a \
+                 JOIN regions r ON r.cell = a), \
+             by_range AS ( \
+                 SELECT p.id, r.cell FROM pts p JOIN regions r \
+                 ON p.cell BETWEEN s2_cell_range_min(r.cell) AND s2_cell_range_max(r.cell)) \
+             SELECT (SELECT count(*) FROM by_range) > 0 \
+                AND NOT EXISTS (SELECT * FROM by_hash EXCEPT ALL SELECT * FROM by_range) \
+                AND NOT EXISTS (SELECT * FROM by_range EXCEPT ALL SELECT * FROM by_hash)",
+        )
+        .expect("spi");
+        assert_eq!(got, Some(true));
     }
 
     #[pg_test]
-    fn test_s2_cell_to_children_level() {
-        let ll = LatLng::from_degrees(49.703498679, 11.770681595);
-        let cell_raw = CellID::from(ll).parent(10);
-        let level = cell_raw.level() as i32 + 1;
-        let expected: Vec<String> = cell_raw.children().iter().map(|c| c.to_token()).collect();
-        let cell = s2_cell_from_token(&cell_raw.to_token());
-        let got: Vec<String> = s2_cell_to_children(cell, level)
-            .map(s2_cell_to_token)
-            .collect();
-        assert_eq!(got, expected);
+    #[should_panic(expected = "invalid level")]
+    fn test_s2_cell_ancestors_invalid_level() {
+        Spi::run("SELECT * FROM s2_cell_ancestors('89c25'::s2cellid, 10, 5)").expect("spi");
     }
 
     #[pg_test]
-    fn test_s2_cell_to_children_default() {
-        let ll = LatLng::from_degrees(49.703498679, 11.770681595);
-        let cell_raw = CellID::from(ll).parent(10);
-        let expected: Vec<String> = cell_raw.children().iter().map(|c| c.to_token()).collect();
-        let cell = s2_cell_from_token(&cell_raw.to_token());
-        let got: Vec<String> = s2_cell_to_children_default(cell)
-            .map(s2_cell_to_token)
-            .collect();
-        assert_eq!(got, expected);
+    fn test_s2_cells_intersect_is_hierarchical() {
+        let got = Spi::get_one::<bool>(
+            "SELECT ARRAY['89c25'::s2cellid] && ARRAY['89c2584'::s2cellid] \
+                AND ARRAY['89c2584'::s2cellid, '47a1cbd4'] && ARRAY['89c25'::s2cellid] \
+                AND NOT ARRAY['89c23'::s2cellid] && ARRAY['89c25'::s2cellid] \
+                AND NOT ARRAY[]::s2cellid[] && ARRAY['89c25'::s2cellid] \
+                AND '{89c25}'::s2cellunion && '{89c2584,47a1cbd4}'::s2cellunion \
+                AND NOT '{89c23}'::s2cellunion && '{89c25}'::s2cellunion",
+        )
+        .expect("spi");
+        assert_eq!(got, Some(true));
     }
 
     #[pg_test]
-    fn test_s2_cell_to_center_child_level() {
-        let ll = LatLng::from_degrees(49.703498679, 11.770681595);
-        let cell_raw = CellID::from(ll).parent(10);
-        let level = cell_raw.level() as i32 + 1;
-        let expected = CellID::from(Cell::from(cell_raw).center())
-            .parent(level as u64)
-            .to_token();
-        let cell = s2_cell_from_token(&cell_raw.to_token());
-        let got = s2_cell_to_center_child(cell, level);
-        assert_eq!(s2_cell_to_token(got), expected);
+    fn test_s2_cell_gin_index_matches_seqscan() {
+        Spi::run(
+            "CREATE TABLE parcels AS \
+             SELECT g AS id, \
+                    ARRAY(SELECT s2_cover_cap(point(-74 + g * 0.003, 40.7), 150 + g, 14 + g % 4, 6)) \
+                        AS cells \
+             FROM generate_series(1, 400) g; \
+             ALTER TABLE parcels ADD COLUMN cover s2cellunion; \
+             UPDATE parcels SET cover = cells::s2cellunion; \
+             CREATE INDEX parcels_cells_gin ON parcels USING gin (cells s2cellid_array_gin_ops); \
+             CREATE INDEX parcels_cover_gin ON parcels USING gin (cover); \
+             ANALYZE parcels",
+        )
+        .expect("spi");
+        let queries = [
+            "ARRAY['89c25'::s2cellid]",
+            "ARRAY(SELECT s2_cover_cap(point(-73.7, 40.7), 2000.0, 12, 8))",
+            "ARRAY[s2_lat_lng_to_cell(point(-73.85, 40.7), 30)]",
+            "ARRAY['47a1cbd4'::s2cellid]",
+        ];
+        for query in queries {
+            let expected = Spi::get_one::<i64>(&format!(
+                "SELECT count(*) FROM parcels WHERE s2_cells_intersect(cells, {query})"
+            ))
+            .expect("spi");
+            Spi::run("SET enable_seqscan = off").expect("spi");
+            let plan = Spi::explain(&format!("SELECT id FROM parcels WHERE cells && {query}"))
+                .expect("explain");
+            assert!(plan.0.to_string().contains("parcels_cells_gin"), "{query}");
+            let by_array = Spi::get_one::<i64>(&format!(
+                "SELECT count(*) FROM parcels WHERE cells && {query}"
+            ))
+            .expect("spi");
+            let by_union = Spi::get_one::<i64>(&format!(
+                "SELECT count(*) FROM parcels WHERE cover && ({query})::s2cellunion"
+            ))
+            .expect("spi");
+            Spi::run("RESET enable_seqscan").expect("spi");
+            assert_eq!(by_array, expected, "{query}");
+            assert_eq!(by_union, expected, "{query}");
+        }
     }
 
     #[pg_test]
-    fn test_s2_cell_to_center_child_default() {
-        let ll = LatLng::from_degrees(49.703498679, 11.770681595);
-        let cell_raw = CellID::from(ll).parent(10);
-        let level = cell_raw.level() as i32 + 1;
-        let expected = CellID::from(Cell::from(cell_raw).center())
-            .parent(level as u64)
-            .to_token();
-        let cell = s2_cell_from_token(&cell_raw.to_token());
-        let got = s2_cell_to_center_child_default(cell);
-        assert_eq!(s2_cell_to_token(got), expected);
+    #[should_panic(expected = "invalid s2cap")]
+    fn test_s2cap_invalid() {
+        Spi::run("SELECT '<(0,95),1>'::s2cap").expect("spi");
     }
 
     #[pg_test]
-    fn test_s2_cell_to_vertices() {
-        let token = "47a1cbd595522b39";
-        let cell_raw = CellID::from_token(token);
-        let expected: Vec<Point> = Cell::from(cell_raw)
-            .vertices()
-            .iter()
-            .map(|v| {
-                let ll = LatLng::from(*v);
-                Point {
-                    x: ll.lng.deg(),
-                    y: ll.lat.deg(),
-                }
-            })
-            .collect();
-        let cell = s2_cell_from_token(token);
-        let got = s2_cell_to_vertices(cell);
-        assert_eq!(got.len(), expected.len());
-        for (a, b) in got.iter().zip(expected.iter()) {
-            assert!((a.x - b.x).abs() < 1e-6);
-            assert!((a.y - b.y).abs() < 1e-6);
-        }
+    #[should_panic(expected = "invalid s2cap")]
+    fn test_s2cap_input_rejects_malformed_structure() {
+        Spi::run("SELECT '<<1,,2)),3'::s2cap").expect("spi");
     }
 
     #[pg_test]
-    fn test_s2_cell_boundary_text() {
-        let token = "47a1cbd595522b39";
-        let cell_raw = CellID::from_token(token);
-        let points: Vec<Point> = Cell::from(cell_raw)
-            .vertices()
-            .iter()
-            .map(|v| {
-                let ll = LatLng::from(*v);
-                Point {
-                    x: ll.lng.deg(),
-                    y: ll.lat.deg(),
-                }
-            })
-            .collect();
-        let expected = format_polygon_points(&points);
-        let cell = s2_cell_from_token(token);
-        let got = s2_cell_boundary_text(cell);
-        assert_eq!(got, expected);
+    #[should_panic(expected = "invalid s2latlngrect")]
+    fn test_s2latlngrect_input_rejects_malformed_structure() {
+        Spi::run("SELECT '1 ,2,3,4'::s2latlngrect").expect("spi");
     }
 
     #[pg_test]
@@ -1287,6 +6175,143 @@ mod tests {
         assert!((got - expected).abs() < 1e-6);
     }
 
+    #[pg_test]
+    fn test_s2_distance_model_wgs84() {
+        let flinders = Point {
+            x: 144.42486789,
+            y: -37.95103342,
+        };
+        let buninyong = Point {
+            x: 143.92649554,
+            y: -37.65282114,
+        };
+        let sphere = s2_great_circle_distance(flinders, buninyong, "m");
+        Spi::run("SET pg_s2.distance_model = 'wgs84'").expect("set GUC");
+        let got = s2_great_circle_distance(flinders, buninyong, "m");
+        assert!((got - 54_972.271).abs() < 1e-3, "{got}");
+        assert!((got - sphere).abs() > 1.0);
+        let pole_to_pole = s2_great_circle_distance(
+            Point { x: 0.0, y: 90.0 },
+            Point { x: 0.0, y: -90.0 },
+            "km",
+        );
+        assert!((pole_to_pole - 20_003.931_458_6).abs() < 1e-6);
+
+        // Caps are sized with the smallest radius of curvature, a(1 - e²).
+        let radius = Spi::get_one::<f64>("SELECT s2_cap_radius_m(s2_cap(point(0, 0), 1000))")
+            .expect("spi")
+            .expect("radius");
+        assert!((radius - 1000.0).abs() < 1e-6);
+        let arc = Spi::get_one::<f64>(
+            "SELECT radians((regexp_match(s2_cap(point(0, 0), 1000)::text, ',([^,]+)>$'))[1]::float8)",
+        )
+        .expect("spi")
+        .expect("arc");
+        assert!((arc * 6_335_439.327 - 1000.0).abs() < 1e-3);
+        // The reported radius is a lower bound on the geodesic distance to
+        // the cap edge: equal along the equatorial meridian, longer eastward.
+        let origin = Point { x: 0.0, y: 0.0 };
+        let edge = |x: f64, y: f64| s2_great_circle_distance(origin, Point { x, y }, "m");
+        let north = edge(0.0, arc.to_degrees());
+        let east = edge(arc.to_degrees(), 0.0);
+        assert!(north >= radius - 1e-3 && north - radius < 0.1, "{north}");
+        assert!(east > radius * 1.006, "{east}");
+    }
+
+    #[pg_test]
+    #[should_panic(expected = "wgs84 distance did not converge")]
+    fn test_s2_distance_model_wgs84_antipodal() {
+        Spi::run("SET pg_s2.distance_model = 'wgs84'").expect("set GUC");
+        let _ = s2_great_circle_distance(Point { x: 0.0, y: 0.0 }, Point { x: 179.7, y: 0.5 }, "m");
+    }
+
+    #[pg_test]
+    fn test_s2_distance_model_wgs84_large_cap() {
+        // 4999 km due south of 60°N ends near 15°N, where the meridional
+        // radius is far smaller than at the cap center.
+        Spi::run("SET pg_s2.distance_model = 'wgs84'").expect("set GUC");
+        let got = Spi::get_one::<bool>(
+            "WITH d AS (SELECT s2_destination_point(point(10, 60), 180, 4999000) AS p) \
+             SELECT s2_great_circle_distance(point(10, 60), p) < 5000000 \
+                AND s2_cap_contains(s2_cap(point(10, 60), 5000000), p) \
+                AND s2_lat_lng_to_cell(p, 6) IN (SELECT s2_cover_cap(point(10, 60), 5000000, 6)) \
+             FROM d",
+        )
+        .expect("spi");
+        assert_eq!(got, Some(true));
+    }
+
+    #[pg_test]
+    fn test_s2_distance_model_wgs84_destination_and_area() {
+        Spi::run("SET pg_s2.distance_model = 'wgs84'").expect("set GUC");
+        let flinders = Point {
+            x: 144.42486789,
+            y: -37.95103342,
+        };
+        let bearing = 306.0 + 52.0 / 60.0 + 5.37 / 3600.0;
+        let got = s2_destination_point(flinders, bearing, 54_972.271);
+        assert!((got.x - 143.92649554).abs() < 1e-7, "{}", got.x);
+        assert!((got.y - -37.65282114).abs() < 1e-7, "{}", got.y);
+
+        // 1° x 1° at the equator: 12308.46 km² on WGS84, 12363 km² on the sphere.
+        let square = "((0,0),(1,0),(1,1),(0,1))";
+        let area = s2_polygon_area_text(square, "km2");
+        assert!((area - 12_308.46).abs() < 0.5, "{area}");
+        Spi::run("SET pg_s2.distance_model = 'sphere'").expect("set GUC");
+        let sphere = s2_polygon_area_text(square, "km2");
+        assert!((sphere - 12_363.3).abs() < 0.5, "{sphere}");
+    }
+
+    #[pg_test]
+    fn test_s2_distance_units() {
+        let a = Point { x: 0.0, y: 0.0 };
+        let b = Point { x: 1.0, y: 0.0 };
+        let m = s2_great_circle_distance(a, b, "m");
+        assert!((s2_great_circle_distance(a, b, "nmi") - m / 1852.0).abs() < 1e-9);
+        assert!((s2_great_circle_distance(a, b, "MI") - m / 1609.344).abs() < 1e-9);
+        assert!((s2_great_circle_distance(a, b, "ft") - m / 0.3048).abs() < 1e-6);
+        assert!((s2_great_circle_distance(a, b, "deg") - 1.0).abs() < 1e-12);
+
+        let got = Spi::get_one::<bool>(
+            "SELECT array_agg(c ORDER BY c) = \
+                (SELECT array_agg(c ORDER BY c) FROM s2_cover_cap(point(2.35, 48.85), 1852, 12) c) \
+             FROM s2_cover_cap(point(2.35, 48.85), 1, 'nmi', 12) c",
+        )
+        .expect("spi");
+        assert_eq!(got, Some(true));
+
+        let sq = Spi::get_one::<f64>(
+            "SELECT s2_polygon_area('((0,0),(1,0),(1,1),(0,1))'::polygon, 'km²') * 1000000 \
+                - s2_polygon_area('((0,0),(1,0),(1,1),(0,1))'::polygon, 'm2')",
+        )
+        .expect("spi")
+        .expect("area");
+        assert!(sq.abs() < 1e-3);
+    }
+
+    #[pg_test]
+    #[should_panic(expected = "invalid unit \"yd\" (supported: m, km, mi, nmi, ft, rad, deg)")]
+    fn test_s2_distance_invalid_unit_lists_supported() {
+        s2_great_circle_distance(Point { x: 0.0, y: 0.0 }, Point { x: 1.0, y: 0.0 }, "yd");
+    }
+
+    #[pg_test]
+    fn test_s2_cell_distance() {
+        let cell = s2_lat_lng_to_cell(Point { x: 20.0, y: 10.0 }, 8);
+        assert_eq!(s2_cell_distance(cell, Point { x: 20.0, y: 10.0 }, "m"), 0.0);
+        let far = Point { x: 20.0, y: 11.0 };
+        let to_cell = s2_cell_distance(cell, far, "m");
+        let to_center = s2_great_circle_distance(far, s2_cell_to_lat_lng(cell), "m");
+        assert!(to_cell > 0.0 && to_cell < to_center);
+        let km = Spi::get_one::<f64>(&format!(
+            "SELECT s2_cell_distance('{}'::s2cellid, point(20, 11), 'km')",
+            s2_cell_to_token(cell)
+        ))
+        .expect("spi")
+        .expect("distance");
+        assert!((km * 1000.0 - to_cell).abs() < 1e-6);
+    }
+
     #[pg_test]
     fn test_s2_cellid_casts() {
         let token = "47a1cbd595522b39";